@@ -1,25 +1,27 @@
 #![allow(non_snake_case)]
+#![allow(clippy::needless_return)]
 mod tokenizer;
 use tokenizer::picture::Picture;
 use tokenizer::fsm::Fsm;
 use tokenizer::picture::Color;
-use tokenizer::tokenizer::tokenize;
+use tokenizer::error::Error;
 
-fn main() {
-    //let picture = Picture::open_pic("test.png");
-    //let mut fsm = Fsm::builder(picture.clone());
+fn main() -> Result<(), Error> {
+    //let picture = Picture::open_pic("test.png")?;
+    //let mut fsm = Fsm::builder(&picture)?;
     //fsm.add_input(Color::from(255, 0, 0));
-    //let f = fsm.build();
+    //let f = fsm.build()?;
     //f.print();
     //print!("{:?}", f.identify(picture.clone()));
     //println!("");
-    let picture2 = Picture::open_pic("loop_test.png");
-    let picture3 = Picture::open_pic("loop_test2.png");
-    let mut fsm = Fsm::builder(&picture2);
+    let picture2 = Picture::open_pic("loop_test.png")?;
+    //let picture3 = Picture::open_pic("loop_test2.png")?;
+    let mut fsm = Fsm::builder(&picture2)?;
     fsm.add_input(Color::from(255, 0, 0));
-    let f = fsm.build();
+    let f = fsm.build()?;
     f.print();
     //print!("{:?}", f.identify(&picture3));
-    //let picture = Picture::open_pic("identifytest.png");
-    //tokenize(&picture);
+    //let picture = Picture::open_pic("identifytest.png")?;
+    //tokenizer::tokenizer::tokenize(&picture)?;
+    return Ok(());
 }
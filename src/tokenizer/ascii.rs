@@ -0,0 +1,260 @@
+#![allow(dead_code)]
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use crate::tokenizer::picture::*;
+use crate::tokenizer::fsm::{Fsm, FSMBuilder, ColorType};
+use crate::tokenizer::error::Error;
+
+// Definitions drawn with characters instead of pixels, so they can be read in a diff
+//
+// A grid is one row of pixels per line, one pixel per character. Whitespace around a row and
+// blank lines are ignored, so grids can be indented inside rust strings. A legend says what
+// color each character is and what it's for.
+//
+// Definition files put the legend on top, one character per line, then a line of ---, then the grid:
+//
+//     // Comments start with //
+//     B = #0000ff function
+//     i = #0094ff input
+//     v = #ff6a00 variable
+//     k = #000000
+//     ---
+//     .B..
+//     iBkki
+//
+// . is always white unless the legend says otherwise.
+
+/// What each character in a drawn definition stands for
+#[derive(Debug, Clone)]
+pub struct Legend {
+    pub chars: HashMap<char, (Color, Option<ColorType>)>,
+}
+
+impl Legend {
+    /// Legend that only knows . is white
+    pub fn new() -> Legend {
+        let mut chars = HashMap::new();
+        chars.insert('.', (WHITE, None));
+        return Legend{chars};
+    }
+
+    /// Adds a character that is just a color, like a loop marker
+    pub fn add(&mut self, ch: char, c: Color) -> &mut Legend {
+        self.chars.insert(ch, (c, None));
+        return self;
+    }
+
+    /// Adds a character for the function color
+    pub fn add_function(&mut self, ch: char, c: Color) -> &mut Legend {
+        self.chars.insert(ch, (c, Some(ColorType::Function)));
+        return self;
+    }
+
+    /// Adds a character for an input color
+    pub fn add_input(&mut self, ch: char, c: Color) -> &mut Legend {
+        self.chars.insert(ch, (c, Some(ColorType::Input)));
+        return self;
+    }
+
+    /// Adds a character for an output color
+    pub fn add_output(&mut self, ch: char, c: Color) -> &mut Legend {
+        self.chars.insert(ch, (c, Some(ColorType::Output)));
+        return self;
+    }
+
+    /// Adds a character for a variable color
+    pub fn add_variable(&mut self, ch: char, c: Color) -> &mut Legend {
+        self.chars.insert(ch, (c, Some(ColorType::Variable)));
+        return self;
+    }
+
+    /// The color the legend says is the function color, if it says
+    /// Errors if it says more than one color is, there's no telling which one is meant
+    fn function(&self) -> Result<Option<Color>, String> {
+        let mut found = None;
+        for (c, t) in self.chars.values() {
+            if *t != Some(ColorType::Function) {continue}
+            if found.is_some_and(|found| found != *c) {
+                return Err(String::from("legend has more than one function color"));
+            }
+            found = Some(*c);
+        }
+        return Ok(found);
+    }
+}
+
+impl Default for Legend {
+    fn default() -> Legend {
+        return Legend::new();
+    }
+}
+
+/// Turns numbered grid lines into a picture
+fn parse_grid<'a>(lines: impl Iterator<Item = (usize, &'a str)>, legend: &Legend, path: Option<PathBuf>) -> Result<Picture, Error> {
+    let mut pixels = vec![];
+    let mut width = None;
+    let mut height = 0;
+    for (n, line) in lines {
+        let line = line.trim();
+        if line.is_empty() {continue}
+
+        let row_width = line.chars().count() as i32;
+        if *width.get_or_insert(row_width) != row_width {
+            return Err(Error::Parse{path, line: n, reason: format!("row is {} wide but the rows above are {} wide", row_width, width.unwrap())});
+        }
+        for (i, ch) in line.chars().enumerate() {
+            let Some((c, _)) = legend.chars.get(&ch) else {
+                return Err(Error::Parse{path, line: n, reason: format!("character {:?} in column {} isn't in the legend", ch, i + 1)});
+            };
+            pixels.push(*c);
+        }
+        height += 1;
+    }
+    let Some(width) = width else {
+        return Err(Error::EmptyImage{path});
+    };
+    return Ok(Picture{pixels, width, height, path});
+}
+
+/// Reads a legend line like `i = #0094ff input`
+fn parse_legend_line(line: &str) -> Option<(char, Color, Option<ColorType>)> {
+    let (ch, rest) = line.split_once('=')?;
+    let mut ch = ch.trim().chars();
+    let (Some(ch), None) = (ch.next(), ch.next()) else {return None};
+
+    let mut words = rest.split_whitespace();
+    let c = Color::from_hex(words.next()?)?;
+
+    let t = match words.next() {
+        None => None,
+        Some("input") => Some(ColorType::Input),
+        Some("output") => Some(ColorType::Output),
+        Some("function") => Some(ColorType::Function),
+        Some("variable") => Some(ColorType::Variable),
+        Some(_) => return None,
+    };
+    if words.next().is_some() {return None}
+    return Some((ch, c, t));
+}
+
+impl Picture {
+    /// Draws a picture from a grid of characters, each one looked up in the legend
+    pub fn from_ascii(grid: &str, legend: &Legend) -> Result<Picture, Error> {
+        return parse_grid(grid.lines().enumerate().map(|(n, line)| (n + 1, line)), legend, None);
+    }
+}
+
+impl Fsm {
+    /// Starts building an fsm out of a grid of characters
+    /// Colors the legend gives a type to are added to the builder, the function color comes from the legend
+    /// if it has one, otherwise it's picked the same way as for a picture
+    /// Errors if the legend has more than one function color
+    pub fn from_ascii(grid: &str, legend: &Legend) -> Result<FSMBuilder, Error> {
+        let p = Picture::from_ascii(grid, legend)?;
+        return Fsm::ascii_builder(&p, legend);
+    }
+
+    /// Starts building an fsm out of a definition file with a legend on top and a grid underneath
+    pub fn load_ascii<P: AsRef<Path>>(path: P) -> Result<FSMBuilder, Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|source| Error::Io{path: path.to_path_buf(), source})?;
+        let error = |line: usize, reason: &str| Error::Parse{path: Some(path.to_path_buf()), line, reason: String::from(reason)};
+
+        let mut legend = Legend::new();
+        let mut lines = text.lines().enumerate().map(|(n, line)| (n + 1, line));
+        loop {
+            let Some((n, line)) = lines.next() else {
+                return Err(error(text.lines().count(), "no --- line between the legend and the grid"));
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {continue}
+            if line == "---" {break}
+            let (ch, c, t) = parse_legend_line(line).ok_or_else(|| error(n, "legend lines look like `i = #0094ff input`"))?;
+            legend.chars.insert(ch, (c, t));
+            legend.function().map_err(|reason| error(n, &reason))?;
+        }
+
+        let p = parse_grid(lines, &legend, Some(path.to_path_buf()))?;
+        return Fsm::ascii_builder(&p, &legend);
+    }
+
+    fn ascii_builder(p: &Picture, legend: &Legend) -> Result<FSMBuilder, Error> {
+        let func_color = legend.function().map_err(|reason| Error::Parse{path: p.path.clone(), line: 0, reason})?;
+        let mut builder = match func_color {
+            Some(func_color) => Fsm::builder_with_function(p, func_color)?,
+            None => Fsm::builder(p)?,
+        };
+        for (c, t) in legend.chars.values() {
+            match t {
+                Some(ColorType::Input) => {builder.add_input(*c);}
+                Some(ColorType::Output) => {builder.add_output(*c);}
+                Some(ColorType::Variable) => {builder.add_variable(*c);}
+                _ => {}
+            }
+        }
+        return Ok(builder);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::fsm::tests::build;
+    use crate::tokenizer::point::Point;
+    use std::fs;
+    use super::*;
+
+    #[test]
+    /// Checks that drawing a definition with characters builds the same fsm as drawing it with pixels
+    fn ascii_matches_png() {
+        let png = Fsm::builder(&Picture::open_pic("./builtin/FStart.png").unwrap()).unwrap()
+            .add_input(Color::from(0, 148, 255))
+            .add_output(Color::from(178, 0, 255))
+            .build().unwrap();
+        let ascii = Fsm::load_ascii("./tests/ascii_definitions/fstart.txt").unwrap().build().unwrap();
+        assert_eq!(ascii.to_text(), png.to_text());
+
+        let png = Fsm::builder(&Picture::open_pic("./tests/definitions/2.png").unwrap()).unwrap().build().unwrap();
+        let ascii = Fsm::load_ascii("./tests/ascii_definitions/plus.txt").unwrap().build().unwrap();
+        assert_eq!(ascii.to_text(), png.to_text());
+    }
+
+    #[test]
+    /// Checks that definitions and pictures can be drawn right in the test
+    fn ascii_inline() {
+        let mut legend = Legend::new();
+        legend.add_function('F', RED).add_input('i', YELLOW).add('k', BLACK);
+        let fsm = build("
+            iF
+            .F
+        ", &legend);
+        assert_eq!(fsm.colors.get(&RED), Some(&ColorType::Function));
+        assert_eq!(fsm.colors.get(&YELLOW), Some(&ColorType::Input));
+
+        let p = Picture::from_ascii("
+            ....
+            .iF.
+            ..F.
+        ", &legend).unwrap();
+        assert_eq!((p.width, p.height), (4, 3));
+        let found = fsm.identify(&p).unwrap();
+        assert_eq!(found.anchor, Point::from(2, 1));
+        assert_eq!(found.inputs(), [Point::from(1, 1)]);
+        assert_eq!(found.function(), [Point::from(2, 1), Point::from(2, 2)]);
+
+        let ragged = Picture::from_ascii("...\n..\n", &legend);
+        assert!(matches!(ragged, Err(Error::Parse{line: 2, ..})));
+        let unknown = Picture::from_ascii("..\n.x\n", &legend);
+        assert!(matches!(unknown, Err(Error::Parse{line: 2, ..})));
+        assert!(matches!(Picture::from_ascii("\n  \n", &legend), Err(Error::EmptyImage{..})));
+
+        // Two function colors, either could be the head
+        let mut two = legend.clone();
+        two.add_function('G', BLUE);
+        assert!(matches!(Fsm::from_ascii("iFG", &two), Err(Error::Parse{..})));
+        two.add_function('G', RED);
+        assert!(Fsm::from_ascii("iFG", &two).is_ok());
+        let path = std::env::temp_dir().join("davinc_two_functions.txt");
+        fs::write(&path, "F = #ff0000 function\ni = #ffff00 input\nG = #0000ff function\n---\niFG\n").unwrap();
+        assert!(matches!(Fsm::load_ascii(&path), Err(Error::Parse{line: 3, ..})));
+    }
+}
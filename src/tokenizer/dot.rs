@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+use std::io::{self, Write};
+use crate::tokenizer::fsm::{Fsm, Transition, ColorType};
+use crate::tokenizer::predicate::ColorPredicate;
+
+/// Escapes text for graphviz labels, works inside quotes and inside <...> labels
+fn escape(s: &str) -> String {
+    return s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
+
+impl Fsm {
+    /// Writes the states and transitions out as a graphviz graph
+    /// Render it with `dot -Tpng fsm.dot -o fsm.png`
+    pub fn write_dot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "digraph fsm {{")?;
+        writeln!(w, "    rankdir=LR;")?;
+        writeln!(w, "    node [shape=circle];")?;
+        // Little arrow pointing at the start state
+        writeln!(w, "    start [shape=point];")?;
+        writeln!(w, "    start -> 0;")?;
+
+        for (i, state) in self.states.iter().enumerate() {
+            // Finish states have no transitions out
+            if state.t.is_empty() {
+                writeln!(w, "    {} [shape=doublecircle];", i)?;
+            }
+            else {
+                writeln!(w, "    {};", i)?;
+            }
+        }
+
+        for (i, state) in self.states.iter().enumerate() {
+            for (destination, transition) in state.t.iter() {
+                let attributes = match transition {
+                    Transition::MoveRelative(rel_state, p) => {
+                        format!("label=\"move ({}, {}) from {}\"", p.x, p.y, rel_state)
+                    }
+                    Transition::Consume(color, predicate) => {
+                        // Swatch of the color next to what it is used for
+                        let role = match self.colors.get(color) {
+                            Some(ColorType::Input) => "input",
+                            Some(ColorType::Output) => "output",
+                            Some(ColorType::Function) => "function",
+                            Some(ColorType::Variable) => "variable",
+                            None => "consume",
+                        };
+                        // Only say what counts as the color if it isn't just the color itself
+                        let accepts = if *predicate == ColorPredicate::Exact(*color) {String::new()}
+                            else {format!(" ({})", escape(&predicate.describe()))};
+                        format!(
+                            "label=<<table border=\"0\" cellspacing=\"2\"><tr><td bgcolor=\"{}\" width=\"12\" height=\"12\"></td><td>{} {}{}</td></tr></table>>, color=\"{}\"",
+                            color.hex(), role, color.hex(), accepts, color.hex()
+                        )
+                    }
+                    Transition::Capture(g) => format!("label=\"capture {}\", style=bold", g),
+                    Transition::EndCapture(g) => format!("label=\"end capture {}\", style=bold", g),
+                    Transition::Round(g) => format!("label=\"round {}\", style=bold", g),
+                    Transition::Epsilon => String::from("label=\"ε\", style=dashed"),
+                    Transition::NotAt(p, predicate) => format!("label=\"not ({}, {}) {}\", style=dotted", p.x, p.y, escape(&predicate.describe())),
+                };
+                writeln!(w, "    {} -> {} [{}];", i, destination, attributes)?;
+            }
+        }
+        writeln!(w, "}}")?;
+        return Ok(());
+    }
+
+    /// The graphviz graph as a string
+    pub fn to_dot(&self) -> String {
+        let mut out = vec![];
+        // Writing to a vec can't fail
+        self.write_dot(&mut out).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+}
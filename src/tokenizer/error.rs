@@ -0,0 +1,81 @@
+#![allow(dead_code)]
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::tokenizer::point::Point;
+use crate::tokenizer::validate::Problem;
+
+/// Everything that can go wrong while loading a picture or building an fsm out of it, or loading a saved fsm
+/// Path is None when whatever went wrong never came from a file
+#[derive(Debug)]
+pub enum Error {
+    Io{path: PathBuf, source: std::io::Error}, // Couldn't open the file
+    Decode{path: PathBuf, source: image::ImageError}, // File opened but isn't an image we understand
+    EmptyImage{path: Option<PathBuf>}, // Image has no pixels in it
+    NoFunctionColor{path: Option<PathBuf>}, // Nothing of the function color to start from
+    MalformedLoop{path: Option<PathBuf>, at: Point, reason: &'static str}, // Loop marker at x doesn't make sense
+    Parse{path: Option<PathBuf>, line: usize, reason: String}, // Saved text fsm is wrong on line x
+    Binary{path: Option<PathBuf>, offset: usize, reason: String}, // Saved binary fsm is wrong at byte x
+    Version{path: Option<PathBuf>, version: u32}, // Saved fsm is from a version we can't read
+    Union{reason: &'static str}, // Fsms can't be put together into one
+    Pattern{reason: String}, // Pattern an fsm is built from in code doesn't make sense
+    Invalid{problems: Vec<Problem>}, // Fsm was put together wrong, see Fsm::validate
+}
+
+/// Writes out a path if there is one, otherwise says it was made in memory
+fn show(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(p) => p.display().to_string(),
+        None => String::from("<in memory>"),
+    }
+}
+
+impl Error {
+    /// The file that caused the error, if there was one
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io{path, ..} | Error::Decode{path, ..} => Some(path),
+            Error::EmptyImage{path} | Error::NoFunctionColor{path} | Error::MalformedLoop{path, ..} => path.as_deref(),
+            Error::Parse{path, ..} | Error::Binary{path, ..} | Error::Version{path, ..} => path.as_deref(),
+            Error::Union{..} | Error::Pattern{..} | Error::Invalid{..} => None,
+        }
+    }
+
+    /// The pixel that caused the error, if it was caused by one
+    pub fn point(&self) -> Option<Point> {
+        match self {
+            Error::MalformedLoop{at, ..} => Some(*at),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io{path, source} => write!(f, "{}: could not open: {}", path.display(), source),
+            Error::Decode{path, source} => write!(f, "{}: could not decode: {}", path.display(), source),
+            Error::EmptyImage{path} => write!(f, "{}: image is empty", show(path)),
+            Error::NoFunctionColor{path} => write!(f, "{}: no function color found in fsm definition", show(path)),
+            Error::MalformedLoop{path, at, reason} => write!(f, "{}: malformed loop marker at ({}, {}): {}", show(path), at.x, at.y, reason),
+            Error::Parse{path, line, reason} => write!(f, "{}:{}: {}", show(path), line, reason),
+            Error::Binary{path, offset, reason} => write!(f, "{}: byte {}: {}", show(path), offset, reason),
+            Error::Version{path, version} => write!(f, "{}: unsupported fsm format version {}", show(path), version),
+            Error::Union{reason} => write!(f, "could not combine alternatives: {}", reason),
+            Error::Pattern{reason} => write!(f, "bad pattern: {}", reason),
+            Error::Invalid{problems} => {
+                let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+                write!(f, "invalid fsm: {}", problems.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io{source, ..} => Some(source),
+            Error::Decode{source, ..} => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -1,29 +1,38 @@
 #![allow(dead_code)]
 use crate::tokenizer::point::*;
 use crate::tokenizer::picture::*;
+use crate::tokenizer::error::Error;
+use crate::tokenizer::vm;
+use crate::tokenizer::matched::{Match, Limit, Limits, Outcome};
+use crate::tokenizer::predicate::ColorPredicate;
+use crate::tokenizer::pattern::Pattern;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
 
 /// The different transitions between states that are possible
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Transition {
     MoveRelative(usize, Point), // Moves relative to x state by p pos
-    Consume(Color), // Consume x color at head_pos
+    Consume(Color, ColorPredicate), // Consume a pixel at head_pos that y takes, it counts as definition color x
     Capture(u8), // Tells identifier to start x capture group 
     EndCapture(u8), // Tells the identifier to stop x capture group
+    Round(u8), // Counts one more time round for x capture group
     Epsilon, // Change to destination state for free
+    NotAt(Point, ColorPredicate), // Passes if the pixel x away from head_pos isn't taken by y, consumes nothing
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ColorType {
     Input,
     Output,
     Function,
+    Variable, // Binds to whatever color it first consumes, every later pixel has to be that color too
 }
 
 /// An abstract object that contains transitions to other indexes in its parent structure Fsm
 /// On entering a state the position of the head is stored
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct State {
     // Transitions
     pub t: Vec<(usize, Transition)>
@@ -35,134 +44,131 @@ impl State {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 /// The base struct for creating a finite state machine, storing it, and executing it
+/// Two fsms are equal if they're the same state for state, build gives back the canonical form so
+/// the same glyph always builds the same fsm
 pub struct Fsm {
     pub states: Vec<State>,
     pub colors: HashMap<Color, ColorType>,
+    // Ways the glyph is allowed to be turned or flipped in a picture, tried in order
+    pub orientations: Vec<Orientation>,
+    // Different variables have to bind to different colors
+    pub distinct_variables: bool,
+    // A match has to take every significant pixel connected to it, nothing more or less
+    pub exact_component: bool,
+    // First state of each alternative the fsm was put together from, in the order they are tried
+    pub alternatives: Vec<usize>,
 }
 
 impl Fsm {
     /// Attempts to identify a picture
-    pub fn identify(&self, p: &Picture) -> Option<HashMap<Color, Vec<Point>>> {
-        fn recurse(
-            head: Point, 
-            p: &Picture, 
-            p_consumed: &HashSet<Point>,
-            f: &Fsm, 
-            state_index: i32, 
-            mut state_points: Vec<Point>, 
-            collect: &HashMap<Color, Vec<Point>>, 
-            epsilon: Option<i32>,
-            capture_groups: &Vec<(u8, i32)>,
-            ended_groups: &HashMap<u8, i32>
-            ) -> Option<HashMap<Color, Vec<Point>>> {
-
-            let cur_state = f.states[state_index as usize].clone();
-            state_points[state_index as usize] = head;
-            // Finish state has no transitions out
-            if cur_state.t.is_empty() {
-                return Some(collect.clone());
+    pub fn identify(&self, p: &Picture) -> Option<Match> {
+        return self.identify_within(p, Limits::default()).found();
+    }
+
+    /// Attempts to identify a picture, giving up if it goes over the limits
+    /// Use it on pictures that can't be trusted, some can take a very long time to say no
+    pub fn identify_within(&self, p: &Picture, limits: Limits) -> Outcome {
+        let mut budget = vm::Budget::new(limits);
+        for o in self.orientations.iter() {
+            let Some(head_pos) = self.head_for(p, *o) else {continue};
+            match self.match_at(p, head_pos, *o, &HashSet::new(), &mut budget) {
+                Ok(Some(found)) => return Outcome::Found(found),
+                Ok(None) => {}
+                Err(limit) => return Outcome::GaveUp(limit),
             }
+        }
+        return Outcome::NotFound;
+    }
 
-            for (destination, transition) in cur_state.t {
-                match transition {
-                    Transition::MoveRelative(rel_state, direction) => {
-                        let new_head = state_points[rel_state] + direction;
-                        if !p.in_bounds(new_head) {continue}
+    /// Attempts to identify a picture with the head of the fsm at a given point
+    /// The point has to be in the picture and count as the function color
+    pub fn identify_at(&self, p: &Picture, head: Point) -> Option<Match> {
+        return self.identify_at_within(p, head, Limits::default()).found();
+    }
 
-                        let mut new_points = state_points.clone();
-                        new_points[destination] = new_head;
+    /// Attempts to identify a picture with the head of the fsm at a given point, giving up if it goes over the limits
+    pub fn identify_at_within(&self, p: &Picture, head: Point, limits: Limits) -> Outcome {
+        if !p.in_bounds(head) || !self.function_predicate().is_some_and(|func| func.matches(p.get_point(head))) {return Outcome::NotFound}
+        let mut budget = vm::Budget::new(limits);
+        for o in self.orientations.iter() {
+            match self.match_at(p, head, *o, &HashSet::new(), &mut budget) {
+                Ok(Some(found)) => return Outcome::Found(found),
+                Ok(None) => {}
+                Err(limit) => return Outcome::GaveUp(limit),
+            }
+        }
+        return Outcome::NotFound;
+    }
 
-                        if let Some(result) = recurse(new_head, p, p_consumed, f, destination as i32, new_points, collect, None, capture_groups, ended_groups) {
-                            return Some(result);
-                        }
-                    }
-                    Transition::Consume(color) => {
-                        let head_color = p.get_point(head);
-                        if head_color == WHITE || head_color == GREEN || p_consumed.contains(&head) {continue}
-
-                        let mut new_collect = collect.clone();
-                        let color_list = new_collect.get_mut(&color).unwrap();
-                        color_list.push(head);
-
-                        let mut new_p_consumed = p_consumed.clone();
-                        new_p_consumed.insert(head);
-
-                        let mut new_capture = vec![];
-                        capture_groups.iter().for_each(|(x, c)| {new_capture.push((*x, c + 1))});
-                        for (x,c) in new_capture.iter() {
-                            if let Some(result) = ended_groups.get(x) {
-                                if *c > *result {continue}
-                            }
-                        }
+    /// Finds every non overlapping match in a picture, in scan order
+    pub fn find_all(&self, p: &Picture) -> Vec<Match> {
+        return self.find_iter(p).collect();
+    }
 
-                        if let Some(result) = recurse(head, p, &new_p_consumed, f, destination as i32, state_points.clone(), &new_collect, None, &new_capture, ended_groups) {
-                            return Some(result);
-                        }
-                    }
-                    Transition::Epsilon => {
-                        // Avoids infinite loop
-                        if let Some(eps) = epsilon {
-                            if eps == destination as i32 {continue}
-                        }
-                        if let Some(result) = recurse(head, p, p_consumed, f, destination as i32, state_points.clone(), collect, Some(state_index), capture_groups, ended_groups) {
-                            return Some(result);
-                        }
-                    }
-                    Transition::Capture(g) => {
-                        let mut new_capture = capture_groups.clone();
-                        new_capture.push((g, 0));
-                        if let Some(result) = recurse(head, p, p_consumed, f, destination as i32, state_points.clone(), collect, Some(state_index), &new_capture, ended_groups) {
-                            return Some(result);
-                        }
-                    }
-                    Transition::EndCapture(g) => {
-                        let mut new_capture = capture_groups.clone();
-                        new_capture.retain(|(x, _)| {*x == g});
-                        let c = new_capture[0].1;
-
-                        let mut new_ended = ended_groups.clone();
-                        if let Some(result) = ended_groups.get(&g) {
-                            if *result != c {continue}
-                        }
-                        else {
-                            new_ended.insert(g, c);
-                        }
-                        let mut new_capture = capture_groups.clone();
-                        new_capture.retain(|(x, _)| {*x != g});
-                        if let Some(result) = recurse(head, p, p_consumed, f, destination as i32, state_points.clone(), collect, Some(state_index), &new_capture, &new_ended) {
-                            return Some(result);
-                        }
+    /// Lazily finds every non overlapping match in a picture, in scan order
+    /// Every function colored pixel is tried as an anchor, pixels consumed by earlier matches can't be reused
+    pub fn find_iter<'a>(&'a self, p: &'a Picture) -> FindIter<'a> {
+        return FindIter{
+            fsm: self,
+            p,
+            func: self.function_predicate(),
+            next: 0,
+            consumed: HashSet::new(),
+            budget: vm::Budget::new(Limits::default()),
+            gave_up: None,
+        };
+    }
+
+    /// The color that marks the head of the fsm
+    /// Only fsms put together by hand can be without one, they don't match anything
+    pub(crate) fn function_color(&self) -> Option<Color> {
+        return self.colors.iter().find_map(|(key, &value)| if value == ColorType::Function {Some(*key)} else {None});
+    }
+
+    /// Which alternative a state belongs to
+    pub(crate) fn alternative_of(&self, state: usize) -> usize {
+        return self.alternatives.partition_point(|a| *a <= state).saturating_sub(1);
+    }
+
+    /// Which picture colors count as the function color, taken from whatever consumes it
+    fn function_predicate(&self) -> Option<ColorPredicate> {
+        let func_color = self.function_color()?;
+        for state in self.states.iter() {
+            for (_, transition) in state.t.iter() {
+                if let Transition::Consume(c, predicate) = transition {
+                    if *c == func_color {
+                        return Some(predicate.clone());
                     }
                 }
             }
-            None
         }
+        return Some(ColorPredicate::Exact(func_color));
+    }
 
-        let mut collect: HashMap<Color, Vec<Point>> = HashMap::new();
-        self.colors.keys().for_each(|k| {
-            collect.insert(*k, vec![]);
-        });
-
-        let func_color = self.colors.iter().find_map(|(key, &value)| if value == ColorType::Function {Some(key)} else {None}).unwrap();
-        
-        // Find toppest leftest function color
-        let mut head_pos = Point::from(-1, -1);
-        'outer: for j in 0..p.height {
+    /// Finds the function pixel the head of the fsm would sit on if the glyph is turned by o
+    /// Unturned that's the toppest leftest one, same as in the definition
+    pub(crate) fn head_for(&self, p: &Picture, o: Orientation) -> Option<Point> {
+        let func = self.function_predicate()?;
+        let undo = o.inverse();
+        let mut best: Option<((i32, i32), Point)> = None;
+        for j in 0..p.height {
             for i in 0..p.width {
-                if p.get(i, j) == *func_color {
-                    head_pos = Point::from(i,j);
-                    break 'outer;
+                if !func.matches(p.get(i, j)) {continue}
+                // Where the pixel would be in the definition's frame
+                let q = undo.apply(Point::from(i, j));
+                if best.is_none_or(|(key, _)| (q.y, q.x) < key) {
+                    best = Some(((q.y, q.x), Point::from(i, j)));
                 }
             }
         }
-        if head_pos.x == -1 || head_pos.y == -1 {return None}
-
-        let mut state_points = vec![Point::from(0, 0); self.states.len()];
-        state_points[0] = head_pos;
+        return best.map(|(_, head)| head);
+    }
 
-        recurse(head_pos, p, &HashSet::new(), self, 0, state_points.clone(), &collect, None, &vec![], &HashMap::new())
+    /// Tries to match the fsm turned by o with its head at anchor, without consuming anything in claimed
+    fn match_at(&self, p: &Picture, anchor: Point, o: Orientation, claimed: &HashSet<Point>, budget: &mut vm::Budget) -> Result<Option<Match>, Limit> {
+        return vm::run(self, p, anchor, o, claimed, budget);
     }
 
     pub fn print(&self) {
@@ -171,7 +177,66 @@ impl Fsm {
         }
     }
 
-    pub fn builder(p: &Picture) -> FSMBuilder {
+    /// Puts several fsms together into one that matches whatever the first of them that matches would
+    /// Every spelling of an operator can be its own definition, Match::alternative says which one it was
+    /// Alternatives that are unions themselves count as all of their own alternatives
+    /// Errors if there's nothing to put together, an alternative isn't valid, or they don't agree on colors,
+    /// orientations or options
+    pub fn union(alternatives: &[Fsm]) -> Result<Fsm, Error> {
+        let Some(first) = alternatives.first() else {
+            return Err(Error::Union{reason: "no alternatives"});
+        };
+        let Some(func_color) = first.function_color() else {
+            return Err(Error::Union{reason: "alternatives have no function color"});
+        };
+        // Start state branches to every alternative in order
+        let mut fsm = Fsm{
+            states: vec![State::new()],
+            colors: HashMap::new(),
+            orientations: first.orientations.clone(),
+            distinct_variables: first.distinct_variables,
+            exact_component: first.exact_component,
+            alternatives: vec![],
+        };
+        for f in alternatives {
+            f.validate()?;
+            if f.function_color() != Some(func_color) {
+                return Err(Error::Union{reason: "alternatives have different function colors"});
+            }
+            if f.orientations != fsm.orientations || f.distinct_variables != fsm.distinct_variables || f.exact_component != fsm.exact_component {
+                return Err(Error::Union{reason: "alternatives are matched with different options"});
+            }
+            for (c, t) in f.colors.iter() {
+                if *fsm.colors.entry(*c).or_insert(*t) != *t {
+                    return Err(Error::Union{reason: "a color is used for different things in different alternatives"});
+                }
+            }
+
+            // States go after the ones already there, so everything pointing at one moves up too
+            let offset = fsm.states.len();
+            fsm.states[0].t.push((offset, Transition::Epsilon));
+            fsm.alternatives.extend(f.alternatives.iter().map(|a| a + offset));
+            for state in f.states.iter() {
+                let t = state.t.iter().map(|(destination, transition)| {
+                    let transition = match transition {
+                        Transition::MoveRelative(rel_state, p) => Transition::MoveRelative(rel_state + offset, *p),
+                        other => other.clone(),
+                    };
+                    (destination + offset, transition)
+                }).collect();
+                fsm.states.push(State{t});
+            }
+        }
+        return fsm.canonical();
+    }
+
+    /// Starts building an fsm out of a definition picture
+    /// The function color is whatever all four corners are, or blue if they don't agree
+    /// Errors if the picture is empty or has nothing of the function color in it
+    pub fn builder(p: &Picture) -> Result<FSMBuilder, Error> {
+        if p.width <= 0 || p.height <= 0 || p.pixels.is_empty() {
+            return Err(Error::EmptyImage{path: p.path.clone()});
+        }
         let mut new_p = p.clone();
         // Get function color
         let result = new_p.four_corners();
@@ -181,13 +246,21 @@ impl Fsm {
             new_p.set(p.width - 1, p.height - 1, WHITE);
             new_p.set(0, p.height - 1, WHITE);
         }
-        let func_color = result.unwrap_or(BLUE);
+        return Fsm::builder_with_function(&new_p, result.unwrap_or(BLUE));
+    }
+
+    /// Starts building an fsm out of a definition picture with a known function color
+    /// Errors if the picture is empty or has nothing of the function color in it
+    pub fn builder_with_function(p: &Picture, func_color: Color) -> Result<FSMBuilder, Error> {
+        if p.width <= 0 || p.height <= 0 || p.pixels.is_empty() {
+            return Err(Error::EmptyImage{path: p.path.clone()});
+        }
 
         // Find upper left corner of the symbol
         let mut head_pos = Point::from(-1, -1);
-        'outer: for j in 0..new_p.height {
-            for i in 0..new_p.width {
-                if new_p.get(i, j) == func_color {
+        'outer: for j in 0..p.height {
+            for i in 0..p.width {
+                if p.get(i, j) == func_color {
                     head_pos = Point::from(i,j);
                     break 'outer;
                 }
@@ -195,36 +268,158 @@ impl Fsm {
         }
 
         let mut colors = HashMap::new();
-        colors.insert(func_color, ColorType::Function);
+        colors.insert(func_color, (ColorType::Function, ColorPredicate::Exact(func_color)));
 
         if head_pos.x == -1 || head_pos.y == -1 {
-            panic!("No function color found in Fsm definition");
+            return Err(Error::NoFunctionColor{path: p.path.clone()});
         }
 
-        FSMBuilder{
+        Ok(FSMBuilder{
             states: vec![State::new()],
             head_pos,
-            p: new_p,
+            p: p.clone(),
+            definition: p.clone(),
+            strict_border: false,
+            aliased: HashSet::new(),
+            tile_shift: None,
+            pattern: None,
             colors,
+            orientations: vec![Orientation::IDENTITY],
+            distinct_variables: false,
+            exact_component: false,
+        })
+    }
+}
+
+impl Hash for Fsm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.states.hash(state);
+        // Maps don't have an order, sorted they hash the same every time
+        let mut colors: Vec<(Color, ColorType)> = self.colors.iter().map(|(c, t)| (*c, *t)).collect();
+        colors.sort_by_key(|(c, _)| (c.r, c.g, c.b));
+        colors.hash(state);
+        self.orientations.hash(state);
+        self.distinct_variables.hash(state);
+        self.exact_component.hash(state);
+        self.alternatives.hash(state);
+    }
+}
+
+/// Iterator over every match of an fsm in a picture, made by Fsm::find_iter
+pub struct FindIter<'a> {
+    fsm: &'a Fsm,
+    p: &'a Picture,
+    // Which picture colors can be anchors, nothing can if the fsm has no function color
+    func: Option<ColorPredicate>,
+    // Index of the next pixel to try as an anchor
+    next: usize,
+    // Pixels taken by matches already handed out
+    consumed: HashSet<Point>,
+    // Limits for the whole picture, every anchor tried spends from them
+    budget: vm::Budget,
+    gave_up: Option<Limit>,
+}
+
+impl FindIter<'_> {
+    /// Stops looking once the search of the whole picture goes over the limits
+    pub fn within(mut self, limits: Limits) -> Self {
+        self.budget = vm::Budget::new(limits);
+        return self;
+    }
+
+    /// The limit that stopped the search early, if one did
+    /// Matches after where it stopped were never looked for
+    pub fn gave_up(&self) -> Option<Limit> {
+        return self.gave_up;
+    }
+}
+
+impl Iterator for FindIter<'_> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        if self.gave_up.is_some() {return None}
+        while self.next < self.p.pixels.len() {
+            let i = self.next as i32;
+            self.next += 1;
+            let anchor = Point::from(i % self.p.width, i / self.p.width);
+            if !self.func.as_ref().is_some_and(|func| func.matches(self.p.get_point(anchor))) || self.consumed.contains(&anchor) {continue}
+
+            for o in self.fsm.orientations.iter() {
+                match self.fsm.match_at(self.p, anchor, *o, &self.consumed, &mut self.budget) {
+                    Ok(Some(found)) => {
+                        self.consumed.extend(found.consumed());
+                        return Some(found);
+                    }
+                    Ok(None) => {}
+                    Err(limit) => {
+                        self.gave_up = Some(limit);
+                        return None;
+                    }
+                }
+            }
         }
+        return None;
     }
 }
 
+// Blue channel of each kind of loop marker pixel
+// A marker with any LOOP_MARKER in it goes round as many times as it likes, otherwise it goes round
+// at most once per pixel in it. Every LOOP_MUST pixel is a time it has to go round.
+pub const LOOP_MARKER: u8 = 0;
+pub const LOOP_MUST: u8 = 128;
+pub const LOOP_MAY: u8 = 64;
+
 pub struct FSMBuilder {
     pub states: Vec<State>,
-    pub colors: HashMap<Color, ColorType>,
+    // Every definition color, what it's for and which picture colors count as it
+    pub colors: HashMap<Color, (ColorType, ColorPredicate)>,
+    pub orientations: Vec<Orientation>,
+    pub distinct_variables: bool,
+    pub exact_component: bool,
     pub head_pos: Point,
     pub p: Picture,
+    // The definition before recurse starts whiting out what it has been through
+    pub definition: Picture,
+    // Every background pixel touching the glyph has to be background in the picture too
+    pub strict_border: bool,
+    // Pixels in the copy closing a tile loop, the loop already took them so they're walked without consuming
+    aliased: HashSet<Point>,
+    // When building a tile, how far it is from its closing copy
+    tile_shift: Option<Point>,
+    // Built from this instead of walking the picture when it's there
+    pub(crate) pattern: Option<Pattern>,
+}
+
+/// What one go round a loop does
+enum LoopBody {
+    // Consume one pixel of a color, the points are every definition pixel it could be standing in for
+    Pixel(Color, Vec<Point>),
+    // Go through a whole tile, its states are numbered from 0 as if it was an fsm of its own
+    Tile(Vec<State>),
+}
+
+/// One pixel the builder is standing on, waiting to look at what's around it
+#[derive(Clone, Copy)]
+struct Walk {
+    head_pos: Point,
+    // State the head stepped onto this pixel from
+    cur_state: usize,
+    // Last state the head is known to be sitting on head_pos
+    settled: usize,
+    // Which of SURROUNDING to look at next
+    next: usize,
 }
 
 impl FSMBuilder {
     /// Consumes the color underneath
     /// Adds another empty state afterwards
-    fn consume(&mut self, c: Color) {
+    pub(crate) fn consume(&mut self, c: Color) {
         let len = self.states.len();
-        let color = *self.color(c).unwrap().0;
+        let (_, predicate) = self.color(c).unwrap();
+        let predicate = predicate.clone();
         // ...0[]
-        self.states[len - 1].t.push((len, Transition::Consume(color)));
+        self.states[len - 1].t.push((len, Transition::Consume(c, predicate)));
         // ...0[Consume(1)]
         self.states.push(State::new());
         // ...0[Consume(1)], 1[]
@@ -234,7 +429,7 @@ impl FSMBuilder {
     /// Move relative to a current state/desired state
     /// p is relative coordinates to the state
     /// Adds another empty state afterwards
-    fn move_rel(&mut self, s: Option<usize>, p: Point) {
+    pub(crate) fn move_rel(&mut self, s: Option<usize>, p: Point) {
         let len = self.states.len();
         // ...0[]
         self.states[len - 1].t.push((len, Transition::MoveRelative(s.unwrap_or(len - 1), p)));
@@ -245,7 +440,7 @@ impl FSMBuilder {
 
     // Starts Capture group for group g
     // Indicates that whenever something is consumed, add to g
-    fn start_capture(&mut self, g: u8) {
+    pub(crate) fn start_capture(&mut self, g: u8) {
         let len = self.states.len();
         // ...0[]
         self.states[len - 1].t.push((len, Transition::Capture(g)));
@@ -256,7 +451,7 @@ impl FSMBuilder {
 
     // Ends Capture group for group g
     // Indicates to stop adding to g
-    fn end_capture(&mut self, g: u8) {
+    pub(crate) fn end_capture(&mut self, g: u8) {
         let len = self.states.len();
         // ...0[]
         self.states[len - 1].t.push((len, Transition::EndCapture(g)));
@@ -265,105 +460,378 @@ impl FSMBuilder {
         // ...0[EndCapture(g)], 1[]
     }
 
-    // Loop group g and continue going p direction while consuming c
-    // Ex loop group 8 and keep heading (0,1) {up}
-    fn loop_please(&mut self, p: Point, c: Color, g: u8) {
-        // Start Capture -> Epsilon(3)/MoveRel -> Consume -> End Capture/Epsilon(1)
-        
-        self.start_capture(g);
+    /// Checks the pixel p away from the head isn't taken by the predicate, without consuming it
+    /// Adds another empty state afterwards
+    fn not_at(&mut self, p: Point, predicate: ColorPredicate) {
         let len = self.states.len();
-        self.states[len - 2].t.push((len + 3, Transition::Epsilon));
-        // ... 0[Capture, Epsilon to 4], 1[]
+        // ...0[]
+        self.states[len - 1].t.push((len, Transition::NotAt(p, predicate)));
+        // ...0[NotAt(p)]
+        self.states.push(State::new());
+        // ...0[NotAt(p)], 1[]
+    }
 
-        let len = self.states.len();
-        self.move_rel(Some(len - 1), p);
-        // ... 0[Capture], 1[MoveRel(pos)], 2[]
-        self.states[len - 1].t.push((len + 1, Transition::Epsilon));
-        // ... 0[Capture], 1[MoveRel(pos),Epsilon(3)]
-        
-        self.consume(c);
-        // ... 0[Capture], 1[Epsilon(3), MoveRel(pos)], 2[Consume(c)], 3[]
+    /// Checks every neighbor that is background around all the definition pixels in at
+    /// is background in the picture too, relative to wherever the head is
+    fn border(&mut self, at: &[Point]) {
+        for pos in SURROUNDING {
+            let background = at.iter().all(|a| {
+                let neighbor = *a + pos;
+                !self.definition.in_bounds(neighbor) || self.definition.get_point(neighbor) == WHITE
+            });
+            if background {
+                self.not_at(pos, ColorPredicate::NonBackground);
+            }
+        }
+    }
 
+    // Counts one more time round for group g
+    pub(crate) fn round(&mut self, g: u8) {
         let len = self.states.len();
-        self.states[len - 1].t.push((len - 3, Transition::Epsilon));
-        // ... 0[Capture], 1[Epsilon(3), MoveRel(pos)], 2[Consume(c)], 3[Epsilon(2)], 4[]
+        // ...0[]
+        self.states[len - 1].t.push((len, Transition::Round(g)));
+        // ...0[Round(g)]
+        self.states.push(State::new());
+        // ...0[Round(g)], 1[]
+    }
+
+    /// Copies a tile's states onto the end, the tile's first state is the current last state
+    fn splice(&mut self, tile: &[State]) {
+        let start = self.states.len() - 1;
+        for (i, state) in tile.iter().enumerate() {
+            let t = state.t.iter().map(|(destination, transition)| {
+                let transition = match transition {
+                    Transition::MoveRelative(rel_state, p) => Transition::MoveRelative(rel_state + start, *p),
+                    transition => transition.clone(),
+                };
+                (destination + start, transition)
+            });
+            if i == 0 {
+                self.states[start].t.extend(t);
+            }
+            else {
+                self.states.push(State{t: t.collect()});
+            }
+        }
+    }
+
+    // One go round group g's loop, p away from where the last one started
+    // The head ends up where this one started, on its copy of the pixel before the marker
+    fn go_round(&mut self, p: Point, body: &LoopBody, g: u8) {
+        let origin = self.states.len() - 1;
+        self.move_rel(Some(origin), p);
+        // ...0[MoveRel(p)], 1[]
+        match body {
+            LoopBody::Pixel(c, at) => {
+                self.consume(*c);
+                // ...0[MoveRel(p)], 1[Consume(c)], 2[]
+                if self.strict_border {
+                    self.border(at);
+                }
+            }
+            LoopBody::Tile(tile) => {
+                let start = self.states.len() - 1;
+                self.splice(tile);
+                // ...0[MoveRel(p)], 1[tile...], n[]
+                self.move_rel(Some(start), Point::zero());
+                // ...0[MoveRel(p)], 1[tile...], n[MoveRel(1)], n+1[]
+            }
+        }
+        self.round(g);
+        // ...0[MoveRel(p)], 1[body...], n[Round(g)], n+1[]
+    }
+
+    // Loop group g going round body p apart each time, at least min times and at most max
+    // Ex loop group 8 and keep heading (0,1) {up}
+    fn loop_please(&mut self, p: Point, body: &LoopBody, g: u8, min: usize, max: Option<usize>) {
+        self.repeat(g, min, max, &|b: &mut FSMBuilder| b.go_round(p, body, g));
+    }
+
+    // Capture group g around going round at least min times and at most max, round adds one go round
+    pub(crate) fn repeat(&mut self, g: u8, min: usize, max: Option<usize>, round: &dyn Fn(&mut FSMBuilder)) {
+        // Start Capture -> go round min times -> either
+        //     unbounded: Epsilon(end)/go round -> End Capture/Epsilon(start of go round)
+        //     bounded: (Epsilon(end)/go round) max - min times -> End Capture
+        self.start_capture(g);
+        // ... 0[Capture], 1[]
+
+        for _ in 0..min {
+            round(self);
+        }
+
+        let Some(max) = max else {
+            let loop_head = self.states.len() - 1;
+            round(self);
+            // ... 1[MoveRel(p)], 2[body...], 3[Round(g)], 4[]
+
+            let len = self.states.len();
+            self.states[loop_head].t.push((len - 1, Transition::Epsilon));
+            // ... 1[MoveRel(p),Epsilon(4)], 2[body...], 3[Round(g)], 4[]
+            self.states[len - 1].t.push((loop_head, Transition::Epsilon));
+            // ... 1[MoveRel(p),Epsilon(4)], 2[body...], 3[Round(g)], 4[Epsilon(1)]
+            self.end_capture(g);
+            // ... 1[MoveRel(p),Epsilon(4)], 2[body...], 3[Round(g)], 4[Epsilon(1), EndCapture], 5[]
+            return;
+        };
+
+        // Every optional go round can give up and jump straight to the end
+        let mut give_ups = vec![];
+        for _ in min..max {
+            give_ups.push(self.states.len() - 1);
+            round(self);
+        }
+        let end = self.states.len() - 1;
+        for state in give_ups {
+            self.states[state].t.push((end, Transition::Epsilon));
+        }
+        // ... 1[MoveRel(p),Epsilon(7)], 2[body...], 3[Round(g)], 4[MoveRel(p),Epsilon(7)], 5[body...], 6[Round(g)], 7[]
         self.end_capture(g);
-        // ... 0[Capture], 1[Epsilon(3), MoveRel(pos)], 2[Consume(c)], 3[Epsilon(2), Capture], 4[]
+        // ... 7[EndCapture], 8[]
     }
-    
+
+    /// Works out what the loop marker length pixels along pos after x repeats
+    /// The marker can be more than one pixel wide, then the copy of what's next to it before and after the
+    /// marker is the tile that repeats, and how long that copy is along pos is how far apart the copies are
+    /// Gives back the body, how far apart copies are, and every marker pixel, then takes them out of the picture
+    fn loop_body(&mut self, x: Point, pos: Point, length: i32) -> Result<(LoopBody, Point, Vec<Point>), Error> {
+        let group = self.p.get_point(x + pos).r;
+        let closing = x + pos * (length + 1);
+        let perp = Point::from(-pos.y, pos.x);
+        let is_marker = |q: Point| self.p.in_bounds(q) && self.marker_kind(self.p.get_point(q)).is_some() && self.p.get_point(q).r == group;
+
+        // Rows of marker as long as the first one, side by side
+        let row = |w: i32| (1..=length).all(|i| is_marker(x + perp * w + pos * i)) && !is_marker(x + perp * w + pos * (length + 1));
+        let mut rows = vec![0];
+        let mut w = 1;
+        while row(w) {
+            rows.push(w);
+            w += 1;
+        }
+        let mut w = -1;
+        while row(w) {
+            rows.push(w);
+            w -= 1;
+        }
+        let marker: Vec<Point> = rows.iter().flat_map(|w| (1..=length).map(move |i| x + perp * *w + pos * i)).collect();
+
+        // Shortest tile that is the same before and after the marker
+        let def = &self.definition;
+        let same = |period: i32| rows.iter().all(|w| (0..period).all(|j| {
+            let before = x + perp * *w - pos * j;
+            let after = closing + perp * *w + pos * (period - 1 - j);
+            def.in_bounds(before) && def.in_bounds(after) && def.get_point(before) == def.get_point(after)
+        }));
+        let longest = def.width.max(def.height);
+        let Some(period) = (1..=longest).find(|period| same(*period)) else {
+            return Err(Error::MalformedLoop{path: self.p.path.clone(), at: closing, reason: "marker is not closed by a copy of what it loops"});
+        };
+
+        for q in marker.iter() {
+            self.p.set_point(*q, WHITE);
+        }
+        if period == 1 && rows.len() == 1 {
+            self.p.set_point(closing, WHITE);
+            let mut at = marker;
+            at.push(closing);
+            return Ok((LoopBody::Pixel(self.definition.get_point(x), at), pos, vec![closing]));
+        }
+
+        // Build the tile on its own, starting from x
+        let band: Vec<Point> = rows.iter().flat_map(|w| (0..period).map(move |j| x + perp * *w - pos * j)).collect();
+        let shift = closing + pos * (period - 1) - x;
+        let mut tile_picture = Picture{pixels: vec![WHITE; self.p.pixels.len()], width: self.p.width, height: self.p.height, path: self.p.path.clone()};
+        for q in band.iter() {
+            tile_picture.set_point(*q, self.definition.get_point(*q));
+        }
+        let mut tile = FSMBuilder{
+            states: vec![State::new()],
+            colors: self.colors.clone(),
+            orientations: vec![],
+            distinct_variables: false,
+            exact_component: false,
+            head_pos: x,
+            p: tile_picture,
+            definition: self.definition.clone(),
+            strict_border: self.strict_border,
+            aliased: HashSet::new(),
+            tile_shift: Some(shift),
+            pattern: None,
+        };
+        tile.recurse(true)?;
+        if let Some(q) = band.iter().find(|q| tile.color(tile.p.get_point(**q)).is_some()) {
+            return Err(Error::MalformedLoop{path: self.p.path.clone(), at: *q, reason: "tile isn't connected to the pixel before the marker"});
+        }
+
+        // The closing copy stands in for the last time round, the head goes to its copy of x
+        let head = x + shift;
+        self.p.set_point(head, WHITE);
+        // Loops inside the tile already went round in the last copy, so the closing copy's markers are done with
+        for q in band.iter() {
+            let c = self.p.get_point(*q + shift);
+            if *q != x && self.color(c).is_some() {
+                self.aliased.insert(*q + shift);
+            }
+            else if self.marker_kind(c).is_some() {
+                self.p.set_point(*q + shift, WHITE);
+            }
+        }
+        return Ok((LoopBody::Tile(tile.states), pos * period, vec![head]));
+    }
+
     /// Adds input color to fsm builder
     pub fn add_input(&mut self, c: Color) -> &mut FSMBuilder {
-        self.colors.insert(c, ColorType::Input);
+        self.colors.insert(c, (ColorType::Input, ColorPredicate::Exact(c)));
         return self;
     }
 
     /// Adds output color to fsm builder
     pub fn add_output(&mut self, c: Color) -> &mut FSMBuilder {
-        self.colors.insert(c, ColorType::Output);
+        self.colors.insert(c, (ColorType::Output, ColorPredicate::Exact(c)));
+        return self;
+    }
+
+    /// Adds a variable color to fsm builder
+    /// It can bind to any significant color but the function color, and keeps that color for the whole match
+    pub fn add_variable(&mut self, c: Color) -> &mut FSMBuilder {
+        self.colors.insert(c, (ColorType::Variable, ColorPredicate::NonBackground));
+        return self;
+    }
+
+    /// Makes different variables bind to different colors
+    pub fn distinct_variables(&mut self) -> &mut FSMBuilder {
+        self.distinct_variables = true;
+        return self;
+    }
+
+    /// Lets any picture color the predicate takes count as definition color c
+    /// For a variable that's which colors it can bind to
+    /// c has to be added first, or be the function color, otherwise this does nothing
+    pub fn accept(&mut self, c: Color, predicate: ColorPredicate) -> &mut FSMBuilder {
+        if let Some((_, old)) = self.colors.get_mut(&c) {
+            *old = predicate;
+        }
+        return self;
+    }
+    
+    /// Lets the fsm match the glyph turned or flipped any of the eight ways
+    pub fn any_orientation(&mut self) -> &mut FSMBuilder {
+        self.orientations = Orientation::ALL.to_vec();
         return self;
     }
     
+    /// Lets every color added so far take any significant color, not just itself
+    /// Significant means anything but white and green, the function color still has to be exact
+    /// since that's how the head gets found
+    pub fn any_color(&mut self) -> &mut FSMBuilder {
+        for (t, predicate) in self.colors.values_mut() {
+            if *t != ColorType::Function {
+                *predicate = ColorPredicate::NonBackground;
+            }
+        }
+        return self;
+    }
+    
+    /// Makes the glyph only match if everything around it that's background in the definition is
+    /// background in the picture too, so it can't be part of something bigger
+    pub fn strict_border(&mut self) -> &mut FSMBuilder {
+        self.strict_border = true;
+        return self;
+    }
+
+    /// Makes a match only count if it consumed the whole connected blob of significant pixels the
+    /// anchor is in, so a glyph can't match the start of a bigger one
+    pub fn exact_component(&mut self) -> &mut FSMBuilder {
+        self.exact_component = true;
+        return self;
+    }
+
     /// Identifies if a selected color is significant to finite state machine
-    fn color(&self, c: Color) -> Option<(&Color, &ColorType)> {
-        return self.colors.get_key_value(&c);
+    fn color(&self, c: Color) -> Option<&(ColorType, ColorPredicate)> {
+        return self.colors.get(&c);
+    }
+
+    /// What kind of loop marker pixel a color is, if it is one
+    /// Colors the definition uses for something else are never markers
+    fn marker_kind(&self, c: Color) -> Option<u8> {
+        if c.g != 0 || c.r == 255 || self.color(c).is_some() {return None}
+        return [LOOP_MARKER, LOOP_MUST, LOOP_MAY].into_iter().find(|kind| *kind == c.b);
     }
 
-    pub fn build(&mut self) -> Fsm {
-        self.recurse(true);
+    /// Walks the definition picture, or the pattern if it was made from one, and turns it into an fsm
+    /// Errors if a loop marker in the picture is malformed or the pattern doesn't make sense
+    pub fn build(&mut self) -> Result<Fsm, Error> {
+        match self.pattern.take() {
+            Some(pattern) => self.compile_pattern(&pattern)?,
+            None => self.recurse(true)?,
+        }
         let fsm = Fsm {
             states: std::mem::take(&mut self.states),
-            colors: std::mem::take(&mut self.colors),
+            colors: std::mem::take(&mut self.colors).into_iter().map(|(c, (t, _))| (c, t)).collect(),
+            orientations: std::mem::take(&mut self.orientations),
+            distinct_variables: self.distinct_variables,
+            exact_component: self.exact_component,
+            alternatives: vec![0],
         };
-        return fsm;
+        return fsm.canonical();
     }
 
-    // Recurses through a symbol and creates an FSM
+    // Walks through a symbol and creates an FSM, depth first like a recursion but on a stack of its own
+    // so long lines don't run out of call stack
     // Consume tag indicates whether it should consume on entering new branch
-    fn recurse(&mut self, consume: bool) {
-        let head_pos = self.head_pos;
-        let cur_state = self.states.len() - 1;
-        let head_color = self.p.get_point(self.head_pos);
-        if consume {
-            self.consume(head_color);
-            self.p.set_point(head_pos, WHITE);
-        }
-
-        for pos in SURROUNDING {
+    fn recurse(&mut self, consume: bool) -> Result<(), Error> {
+        let mut walks = vec![self.enter(consume)];
+        while let Some(walk) = walks.last_mut() {
+            // Looked all around, go back to whoever stepped here
+            let Some(&pos) = SURROUNDING.get(walk.next) else {
+                walks.pop();
+                continue;
+            };
+            walk.next += 1;
+            let Walk{head_pos, cur_state, settled, ..} = *walk;
             let next_position = head_pos + pos;
             if !self.p.in_bounds(next_position) {continue}
 
             let cur_color = self.p.get(next_position.x, next_position.y);
 
             // SPECIAL LOOP CODE:
-            // It can be black -> red as long as green and blue are 0 and red != 255
-            if cur_color.g == 0 && cur_color.b == 0 && cur_color.r != 255 {
+            // It can be black -> red as long as green is 0 and red != 255, red is the capture group
+            // Blue says how many times that pixel's worth of marker goes round, see LOOP_MARKER
+            if let Some(kind) = self.marker_kind(cur_color) {
                 let mut black_count = 1;
                 let mut black_pos = next_position;
+                let mut kinds = vec![kind];
                 loop {
                     black_pos += pos; 
                     if !self.p.in_bounds(black_pos) {
                         break;
                     }
                     let cur_black = self.p.get_point(black_pos);
-                    if cur_black == cur_color {
-                        black_count += 1;
-                    }
-                    else {
-                        break;
+                    match self.marker_kind(cur_black) {
+                        Some(kind) if cur_black.r == cur_color.r => {
+                            black_count += 1;
+                            kinds.push(kind);
+                        }
+                        _ => break,
                     }
                 }
                 if black_count < 2 {continue}
-                for i in 0..=black_count {
-                    self.p.set_point(next_position + (pos * i), WHITE);
+                // The marker has to be closed off by another copy of what's being looped
+                if !self.p.in_bounds(black_pos) {
+                    return Err(Error::MalformedLoop{path: self.p.path.clone(), at: next_position, reason: "marker runs off the edge of the image"});
                 }
+                let (body, p, closing) = self.loop_body(head_pos, pos, black_count)?;
 
-                self.loop_please(pos, head_color, cur_color.r);
-                // Essentially goes to one after last black, pretend you are there already, don't
-                // reconsume, and go look around
-                if self.p.in_bounds(black_pos) {
-                    self.head_pos = black_pos;
-                    self.recurse(false);
+                // Walking other branches moved the head, the loop has to start from here
+                if self.states.len() - 1 != settled {
+                    self.move_rel(Some(cur_state), Point::zero());
                 }
+                let min = kinds.iter().filter(|k| **k == LOOP_MUST).count();
+                let max = if kinds.contains(&LOOP_MARKER) {None} else {Some(kinds.len())};
+                self.loop_please(p, &body, cur_color.r, min, max);
+                // Essentially goes to the closing copy, pretend you are there already, don't
+                // reconsume, and go look around
+                self.head_pos = closing[0];
+                walks.push(self.enter(false));
             }
             // If we don't care about the color of the surrounding pixel go to the next one
             else if self.color(cur_color).is_none() {continue}
@@ -371,19 +839,73 @@ impl FSMBuilder {
             else {
                 self.move_rel(Some(cur_state), pos);
                 self.head_pos = next_position;
-                self.recurse(true);
+                // Part of a closing copy, the loop already took it
+                if self.aliased.remove(&next_position) {
+                    self.p.set_point(next_position, WHITE);
+                    walks.push(self.enter(false));
+                }
+                else {
+                    walks.push(self.enter(true));
+                }
             }
         } 
+        return Ok(());
+    }
+
+    /// Steps onto head_pos, consuming it if asked, and gives back where to look around from
+    fn enter(&mut self, consume: bool) -> Walk {
+        let head_pos = self.head_pos;
+        let cur_state = self.states.len() - 1;
+        let head_color = self.p.get_point(self.head_pos);
+        if consume {
+            self.consume(head_color);
+            self.p.set_point(head_pos, WHITE);
+        }
+        if self.strict_border {
+            // Tiles have to fit in as the first copy and as the last
+            match self.tile_shift {
+                Some(shift) => self.border(&[head_pos, head_pos + shift]),
+                None => self.border(&[head_pos]),
+            }
+        }
+        // Last state the head is known to be sitting on head_pos
+        let settled = self.states.len() - 1;
+        return Walk{head_pos, cur_state, settled, next: 0};
     }
 }
 
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::tokenizer::picture;
+    use crate::tokenizer::ascii::Legend;
+    use crate::tokenizer::pattern::Pattern;
     use super::*;
     use std::fs;
-    
+
+    /// Blue function, yellow inputs, red outputs and black loop markers, what most of the tests draw with
+    pub(crate) fn legend() -> Legend {
+        let mut legend = Legend::new();
+        legend.add_function('F', BLUE).add_input('i', YELLOW).add_output('o', RED).add('k', BLACK);
+        return legend;
+    }
+
+    /// Builds a definition drawn in a test, it has to be a good one
+    pub(crate) fn build(def: &str, legend: &Legend) -> Fsm {
+        return Fsm::from_ascii(def, legend).unwrap().build().unwrap();
+    }
+
+    /// Every definition picture in the test folders, with and without loops
+    pub(crate) fn definitions() -> Vec<Picture> {
+        let mut definitions = vec![];
+        for dir in ["./tests/definitions", "./tests/loop_definitions"] {
+            for path in fs::read_dir(dir).unwrap() {
+                definitions.push(picture::Picture::open_pic(path.unwrap().path()).unwrap());
+            }
+        }
+        return definitions;
+    }
+
     #[test]
     /// Checks that all of the fsm definition tests compile into an fsm regardless of correctness
     fn fsm_compiles() {
@@ -392,9 +914,8 @@ mod tests {
         // High rent but utilities are low
         let paths = fs::read_dir("./tests/definitions").unwrap();
         for path in paths {
-            let p = picture::Picture::open_pic(path.unwrap().path().to_str().unwrap());
-            let fsm_builder = Fsm::builder(&p);
-            let fsm = fsm_builder.build();
+            let p = picture::Picture::open_pic(path.unwrap().path()).unwrap();
+            let fsm = Fsm::builder(&p).unwrap().build().unwrap();
             assert!(fsm.states.len() > 1);
         }
     }
@@ -403,9 +924,8 @@ mod tests {
     fn fsm_identifies_self() {
         let paths = fs::read_dir("./tests/definitions").unwrap();
         for path in paths {
-            let p = picture::Picture::open_pic(path.unwrap().path().to_str().unwrap());
-            let fsm_builder = Fsm::builder(&p);
-            let fsm = fsm_builder.build();
+            let p = picture::Picture::open_pic(path.unwrap().path()).unwrap();
+            let fsm = Fsm::builder(&p).unwrap().build().unwrap();
             assert!(fsm.identify(&p).is_some());
         }
     }
@@ -414,10 +934,640 @@ mod tests {
     fn loop_fsm_compiles() {
         let paths = fs::read_dir("./tests/loop_definitions").unwrap();
         for path in paths {
-            let p = picture::Picture::open_pic(path.unwrap().path().to_str().unwrap());
-            let fsm_builder = Fsm::builder(&p);
-            let fsm = fsm_builder.build();
+            let p = picture::Picture::open_pic(path.unwrap().path()).unwrap();
+            let fsm = Fsm::builder(&p).unwrap().build().unwrap();
             assert!(fsm.states.len() > 1);
         }
     }
+    #[test]
+    /// Checks that bad files and bad definitions come back as errors instead of panicking
+    fn bad_definitions_error() {
+        let missing = picture::Picture::open_pic("./tests/definitions/missing.png");
+        assert!(matches!(missing, Err(Error::Io{..})));
+        assert_eq!(missing.unwrap_err().path(), Some(std::path::Path::new("./tests/definitions/missing.png")));
+
+        let blank = Picture{pixels: vec![WHITE; 9], width: 3, height: 3, path: None};
+        assert!(matches!(Fsm::builder(&blank), Err(Error::NoFunctionColor{..})));
+
+        let empty = Picture{pixels: vec![], width: 0, height: 0, path: None};
+        assert!(matches!(Fsm::builder(&empty), Err(Error::EmptyImage{..})));
+
+        // Loop marker that never gets closed off
+        let mut open_loop = Picture{pixels: vec![WHITE; 4], width: 4, height: 1, path: None};
+        open_loop.set(1, 0, BLUE);
+        open_loop.set(2, 0, BLACK);
+        open_loop.set(3, 0, BLACK);
+        let err = Fsm::builder(&open_loop).unwrap().build().unwrap_err();
+        assert!(matches!(err, Error::MalformedLoop{..}));
+        assert_eq!(err.point(), Some(Point::from(2, 0)));
+    }
+    #[test]
+    /// Checks that a glyph drawn twice gets found twice, in scan order
+    fn finds_every_copy() {
+        let def = picture::Picture::open_pic("./tests/definitions/2.png").unwrap();
+        let fsm = Fsm::builder(&def).unwrap().build().unwrap();
+
+        // Two copies of the plus side by side, the second one a row lower
+        let mut p = Picture{pixels: vec![WHITE; 10 * 6], width: 10, height: 6, path: None};
+        for j in 0..def.height {
+            for i in 0..def.width {
+                p.set(i, j, def.get(i, j));
+                p.set(i + 5, j + 1, def.get(i, j));
+            }
+        }
+        assert!(fsm.identify(&p).is_some());
+
+        let found = fsm.find_all(&p);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].anchor, Point::from(2, 1));
+        assert_eq!(found[1].anchor, Point::from(7, 2));
+        assert_eq!(found[0].consumed().count(), 5);
+        assert!(found[1].consumed().all(|point| point.x >= 5));
+    }
+    #[test]
+    /// Checks that matching can be pinned to a point
+    fn identifies_at_point() {
+        let p = picture::Picture::open_pic("./tests/definitions/2.png").unwrap();
+        let fsm = Fsm::builder(&p).unwrap().build().unwrap();
+
+        assert!(fsm.identify_at(&p, Point::from(2, 1)).is_some());
+        assert_eq!(fsm.identify_at(&p, Point::from(2, 1)), fsm.identify(&p));
+        // The middle of the plus is the right color but the wrong spot
+        assert!(fsm.identify_at(&p, Point::from(2, 2)).is_none());
+        assert!(fsm.identify_at(&p, Point::from(0, 0)).is_none());
+        assert!(fsm.identify_at(&p, Point::from(-1, 7)).is_none());
+    }
+    #[test]
+    /// Checks that a lopsided glyph is found however it's turned, and that the turn gets reported
+    fn identifies_any_orientation() {
+        let def = picture::Picture::open_pic("./tests/definitions/4.png").unwrap();
+        let strict = Fsm::builder(&def).unwrap().build().unwrap();
+        let fsm = Fsm::builder(&def).unwrap().any_orientation().build().unwrap();
+
+        for o in Orientation::ALL {
+            let p = def.orient(o);
+            assert!(fsm.identify(&p).is_some(), "{:?}", o);
+            let found = fsm.find_all(&p);
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].orientation, o);
+            assert_eq!(found[0].consumed().count(), 11);
+            if o != Orientation::IDENTITY {
+                assert!(strict.identify(&p).is_none(), "{:?}", o);
+            }
+        }
+    }
+    /// Hollow square of a color, w by h with a white border around it
+    fn ring(w: i32, h: i32, c: Color) -> Picture {
+        let mut p = Picture{pixels: vec![WHITE; ((w + 2) * (h + 2)) as usize], width: w + 2, height: h + 2, path: None};
+        for i in 1..=w {
+            p.set(i, 1, c);
+            p.set(i, h, c);
+        }
+        for j in 1..=h {
+            p.set(1, j, c);
+            p.set(w, j, c);
+        }
+        return p;
+    }
+
+    #[test]
+    /// Checks that loops sharing a capture group have to be the same length
+    fn loops_share_length() {
+        let def = picture::Picture::open_pic("./loop_test.png").unwrap();
+        let fsm = Fsm::builder(&def).unwrap().build().unwrap();
+
+        for size in [3, 4, 10, 25] {
+            let found = fsm.identify(&ring(size, size, YELLOW)).unwrap();
+            assert_eq!(found.consumed().count(), (4 * size - 4) as usize);
+            // One run per side of the square, none of them sharing pixels
+            assert_eq!(found.captures.len(), 4);
+            assert!(found.groups(0).all(|c| c.count == (size - 3) as usize && c.pixels.len() == c.count));
+            let sides: HashSet<Point> = found.groups(0).flat_map(|c| c.pixels.iter().copied()).collect();
+            assert_eq!(sides.len(), 4 * (size - 3) as usize);
+        }
+        assert!(fsm.identify(&ring(5, 7, YELLOW)).is_none());
+        assert!(fsm.identify(&ring(7, 5, YELLOW)).is_none());
+    }
+
+    #[test]
+    /// Checks that a long loop is matched in one go without blowing up
+    fn long_loop_matches() {
+        let def = picture::Picture::open_pic("./tests/loop_definitions/1.png").unwrap();
+        let fsm = Fsm::builder(&def).unwrap().build().unwrap();
+
+        let mut p = Picture{pixels: vec![WHITE; 5 * 2000], width: 2000, height: 5, path: None};
+        p.set(0, 1, BLUE);
+        p.set(0, 3, BLUE);
+        for i in 1..2000 {
+            p.set(i, 2, BLUE);
+        }
+        let found = fsm.identify(&p).unwrap();
+        assert_eq!(found.of_color(BLUE).len(), 2001);
+        assert_eq!(found.function()[..3], [Point::from(0, 1), Point::from(1, 2), Point::from(2, 2)]);
+    }
+
+    #[test]
+    /// Checks what a match reports about itself
+    fn match_reports_roles() {
+        let def = picture::Picture::open_pic("./builtin/FStart.png").unwrap();
+        let fsm = Fsm::builder(&def).unwrap()
+            .add_input(Color::from(0, 148, 255))
+            .add_output(Color::from(178, 0, 255))
+            .build().unwrap();
+        // The loop markers stand for more of the color before them
+        let mut p = def.clone();
+        p.set(1, 2, Color::from(0, 148, 255));
+        p.set(2, 2, Color::from(0, 148, 255));
+        p.set(8, 2, Color::from(178, 0, 255));
+        p.set(9, 2, Color::from(178, 0, 255));
+
+        let found = fsm.identify(&p).unwrap();
+        assert_eq!(found.anchor, Point::from(4, 0));
+        assert_eq!(found.orientation, Orientation::IDENTITY);
+        assert_eq!(found.inputs(), (0..4).rev().map(|x| Point::from(x, 2)).collect::<Vec<_>>());
+        assert_eq!(found.outputs(), (7..11).map(|x| Point::from(x, 2)).collect::<Vec<_>>());
+        assert_eq!(found.function().len(), 7);
+        assert_eq!(found.count(0), Some(3));
+        assert_eq!(found.count(28), Some(3));
+        assert_eq!(found.groups(0).next().unwrap().pixels, [Point::from(2, 2), Point::from(1, 2), Point::from(0, 2)]);
+        assert_eq!(found.groups(28).next().unwrap().pixels, [Point::from(8, 2), Point::from(9, 2), Point::from(10, 2)]);
+        assert_eq!(found.count(1), None);
+        assert_eq!(found.bounds, (Point::from(0, 0), Point::from(10, 4)));
+        assert_eq!(found.by_color()[&BLUE], found.function());
+
+        // Everything non white in the picture is part of the glyph, so cutting it out gives it back
+        let cut = found.picture(&p);
+        assert_eq!((cut.width, cut.height), (p.width, p.height));
+        assert_eq!(cut.pixels, p.pixels);
+    }
+
+    #[test]
+    /// Checks that the graphviz export has every state and transition in it
+    fn exports_dot() {
+        let def = picture::Picture::open_pic("./builtin/FStart.png").unwrap();
+        let fsm = Fsm::builder(&def).unwrap()
+            .add_input(Color::from(0, 148, 255))
+            .add_output(Color::from(178, 0, 255))
+            .build().unwrap();
+
+        let dot = fsm.to_dot();
+        assert!(dot.starts_with("digraph fsm {"));
+        assert!(dot.trim_end().ends_with('}'));
+        let transitions: usize = fsm.states.iter().map(|s| s.t.len()).sum();
+        // Every transition plus the arrow into the start state
+        assert_eq!(dot.matches(" -> ").count(), transitions + 1);
+        assert!(dot.contains("bgcolor=\"#0094ff\""));
+        assert!(dot.contains("label=\"capture 28\""));
+        assert!(dot.contains("label=\"end capture 0\""));
+        assert!(dot.contains(&format!("{} [shape=doublecircle];", fsm.states.len() - 1)));
+
+        // Set names can be anything that's one word, labels can't
+        let mut legend = Legend::new();
+        legend.add_function('F', BLUE).add_input('r', RED);
+        let fsm = Fsm::from_ascii("rF", &legend).unwrap().accept(RED, ColorPredicate::Set(String::from("r&d<1>\""), vec![RED])).build().unwrap();
+        let dot = fsm.to_dot();
+        assert!(dot.contains("(r&amp;d&lt;1&gt;&quot;)"));
+        assert!(!dot.contains("r&d"));
+    }
+
+    #[test]
+    /// Checks that each pixel has to be the color the definition says unless any_color is asked for
+    fn consume_is_color_strict() {
+        let mut legend = Legend::new();
+        legend.add_function('F', RED).add_input('i', YELLOW).add('k', BLACK).add('g', GREEN);
+        let def = "
+            iF
+            .F
+        ";
+        let strict = build(def, &legend);
+        let loose = Fsm::from_ascii(def, &legend).unwrap().any_color().build().unwrap();
+
+        let same = Picture::from_ascii("iF\n.F\n", &legend).unwrap();
+        let other = Picture::from_ascii("kF\n.F\n", &legend).unwrap();
+        let green = Picture::from_ascii("gF\n.F\n", &legend).unwrap();
+        assert!(strict.identify(&same).is_some());
+        assert!(strict.identify(&other).is_none());
+        assert!(loose.identify(&same).is_some());
+        // Pixels are still reported with the definition color that consumed them
+        assert_eq!(loose.identify(&other).unwrap().inputs(), [Point::from(0, 0)]);
+        assert!(loose.identify(&green).is_none());
+
+        let loaded = Fsm::from_text(&loose.to_text()).unwrap();
+        assert!(loaded.identify(&other).is_some());
+        assert!(Fsm::from_bytes(&loose.to_bytes()).unwrap().identify(&other).is_some());
+        assert!(Fsm::from_bytes(&strict.to_bytes()).unwrap().identify(&other).is_none());
+    }
+
+    #[test]
+    /// Checks that variables bind to the first color they see and keep it
+    fn variables_bind() {
+        let mut legend = Legend::new();
+        legend.add_function('F', BLUE).add_variable('a', RED).add_variable('b', YELLOW);
+        legend.add('x', Color::from(10, 200, 30)).add('y', Color::from(90, 0, 90));
+        let def = "aaFb";
+        let fsm = build(def, &legend);
+        let distinct = Fsm::from_ascii(def, &legend).unwrap().distinct_variables().build().unwrap();
+
+        let p = Picture::from_ascii("xxFy", &legend).unwrap();
+        let found = fsm.identify(&p).unwrap();
+        assert_eq!(found.binding(RED), Some(Color::from(10, 200, 30)));
+        assert_eq!(found.binding(YELLOW), Some(Color::from(90, 0, 90)));
+        assert_eq!(found.binding(BLUE), None);
+        assert_eq!(found.variables().len(), 3);
+        assert!(distinct.identify(&p).is_some());
+
+        // Same variable, different colors
+        assert!(fsm.identify(&Picture::from_ascii("xyFy", &legend).unwrap()).is_none());
+        // Different variables, same color
+        let same = Picture::from_ascii("xxFx", &legend).unwrap();
+        assert!(fsm.identify(&same).is_some());
+        assert!(distinct.identify(&same).is_none());
+        // Variables never take the function color
+        assert!(fsm.identify(&Picture::from_ascii("xxFF", &legend).unwrap()).is_none());
+
+        for loaded in [Fsm::from_text(&distinct.to_text()).unwrap(), Fsm::from_bytes(&distinct.to_bytes()).unwrap()] {
+            assert!(loaded.distinct_variables);
+            assert_eq!(loaded.colors.get(&RED), Some(&ColorType::Variable));
+            assert!(loaded.identify(&same).is_none());
+            assert_eq!(loaded.identify(&p), distinct.identify(&p));
+        }
+    }
+
+    #[test]
+    /// Checks that a strict border stops a glyph matching when something touches it or it's part of something bigger
+    fn strict_border() {
+        let mut legend = Legend::new();
+        legend.add_function('B', BLUE).add('k', BLACK);
+        let def = "
+            ..B..
+            .BBB.
+            ..B..
+        ";
+        let loose = build(def, &legend);
+        let strict = Fsm::from_ascii(def, &legend).unwrap().strict_border().build().unwrap();
+        assert!(strict.states.iter().flat_map(|s| s.t.iter()).any(|(_, t)| matches!(t, Transition::NotAt(..))));
+
+        let alone = Picture::from_ascii(".....\n..B..\n.BBB.\n..B..\n.....\n", &legend).unwrap();
+        let touched = Picture::from_ascii(".....\n..B..\n.BBB.\n..B..\n...k.\n", &legend).unwrap();
+        let bigger = Picture::from_ascii("...B...\n...B...\n.BBBBB.\n...B...\n...B...\n", &legend).unwrap();
+        assert!(loose.identify(&alone).is_some());
+        assert!(loose.identify(&touched).is_some());
+        assert!(loose.identify_at(&bigger, Point::from(3, 1)).is_some());
+        assert!(strict.identify(&alone).is_some());
+        assert!(strict.identify(&touched).is_none());
+        assert!(strict.identify_at(&bigger, Point::from(3, 1)).is_none());
+        // The edge of the picture counts as background
+        assert!(strict.identify(&Picture::from_ascii(".B.\nBBB\n.B.\n", &legend).unwrap()).is_some());
+
+        // Loops check around every pixel they go through
+        let def = picture::Picture::open_pic("./loop_test.png").unwrap();
+        let strict = Fsm::builder(&def).unwrap().strict_border().build().unwrap();
+        let mut p = ring(10, 10, YELLOW);
+        assert!(strict.identify(&p).is_some());
+        p.set(5, 0, BLACK);
+        assert!(strict.identify(&p).is_none());
+        assert!(Fsm::from_text(&strict.to_text()).unwrap().identify(&p).is_none());
+        assert!(Fsm::from_bytes(&strict.to_bytes()).unwrap().identify(&ring(10, 10, YELLOW)).is_some());
+    }
+
+    #[test]
+    /// Checks that an exact component match has to take everything connected to it
+    fn exact_component() {
+        let mut legend = Legend::new();
+        legend.add_function('B', BLUE).add('k', BLACK).add('g', GREEN);
+        let def = "
+            ..B..
+            .BBB.
+            ..B..
+        ";
+        let loose = build(def, &legend);
+        let exact = Fsm::from_ascii(def, &legend).unwrap().exact_component().build().unwrap();
+
+        let alone = Picture::from_ascii(".B...\nBBB..\n.B..k\n", &legend).unwrap();
+        let longer = Picture::from_ascii(".B...\nBBB..\n.B...\n.B...\n", &legend).unwrap();
+        let touched = Picture::from_ascii(".B...\nBBB..\n.Bk..\n", &legend).unwrap();
+        let green = Picture::from_ascii(".B...\nBBB..\n.Bg..\n", &legend).unwrap();
+        for p in [&alone, &longer, &touched, &green] {
+            assert!(loose.identify(p).is_some());
+        }
+        assert!(exact.identify(&alone).is_some());
+        assert!(exact.identify(&longer).is_none());
+        assert!(exact.identify(&touched).is_none());
+        // Green isn't significant so it doesn't count as touching
+        assert!(exact.identify(&green).is_some());
+
+        // Two glyphs stuck together are one component
+        let two = Picture::from_ascii(".B.B.\nBBBBB\n.B.B.\n", &legend).unwrap();
+        assert_eq!(exact.find_all(&two).len(), 0);
+        assert_eq!(loose.find_all(&two).len(), 1);
+        let apart = Picture::from_ascii(".B...B.\nBBB.BBB\n.B...B.\n", &legend).unwrap();
+        assert_eq!(exact.find_all(&apart).len(), 2);
+
+        for loaded in [Fsm::from_text(&exact.to_text()).unwrap(), Fsm::from_bytes(&exact.to_bytes()).unwrap()] {
+            assert!(loaded.exact_component);
+            assert!(loaded.identify(&longer).is_none());
+        }
+    }
+
+    #[test]
+    /// Checks that must and may marker pixels bound how many times a loop goes round
+    fn bounded_loops() {
+        let mut legend = Legend::new();
+        legend.add_function('F', BLUE).add_input('i', YELLOW).add('k', BLACK);
+        legend.add('M', Color::from(0, 0, LOOP_MUST)).add('Q', Color::from(0, 0, LOOP_MAY));
+        let line = |n: usize| Picture::from_ascii(&format!("F{}", "i".repeat(n)), &legend).unwrap();
+        // Shortest and longest line each definition takes, counting X and the loop pixels
+        let cases = [
+            ("Fikki", 1, None),
+            ("FikkMi", 2, None),
+            ("FiMMMki", 4, None),
+            ("FiMMi", 3, Some(3)),
+            ("FiMQi", 2, Some(3)),
+            ("FiQQi", 1, Some(3)),
+        ];
+        for (def, shortest, longest) in cases {
+            let fsm = Fsm::from_ascii(def, &legend).unwrap().exact_component().build().unwrap();
+            for n in 1..9 {
+                let found = fsm.identify(&line(n));
+                let expected = n >= shortest && longest.is_none_or(|longest| n <= longest);
+                assert_eq!(found.is_some(), expected, "{} on {}", def, n);
+                if let Some(found) = found {
+                    assert_eq!(found.count(0), Some(n - 1), "{} on {}", def, n);
+                }
+            }
+            // Without exact component it takes as much as it's allowed
+            let loose = build(def, &legend);
+            assert_eq!(loose.identify(&line(8)).unwrap().inputs().len(), longest.unwrap_or(8));
+        }
+    }
+
+    #[test]
+    /// Checks that colors the definition uses are never taken for loop markers even if they look like one
+    fn registered_colors_arent_markers() {
+        let navy = Color::from(0, 0, LOOP_MUST);
+        let mut legend = Legend::new();
+        legend.add_function('F', navy).add_input('i', YELLOW);
+        let fsm = build("FFFi", &legend);
+        assert!(fsm.identify(&Picture::from_ascii("FFFi", &legend).unwrap()).is_some());
+
+        let mut legend = Legend::new();
+        legend.add_function('F', BLUE).add_input('i', navy);
+        let fsm = build("Fiii", &legend);
+        assert_eq!(fsm.identify(&Picture::from_ascii("Fiii", &legend).unwrap()).unwrap().inputs().len(), 3);
+    }
+
+    #[test]
+    /// Checks that a marker next to more than one pixel repeats the whole tile, and counts rounds not pixels
+    fn tile_loops() {
+        let legend = legend();
+        let build = |def: &str| Fsm::from_ascii(def, &legend).unwrap().exact_component().build().unwrap();
+
+        // Two pixel thick bar
+        let bar = build("
+            Fikki
+            .ikki
+        ");
+        for n in 1..6 {
+            let p = Picture::from_ascii(&format!("F{}\n.{}\n", "i".repeat(n), "i".repeat(n)), &legend).unwrap();
+            let found = bar.identify(&p).unwrap();
+            assert_eq!(found.count(0), Some(n - 1));
+            assert_eq!(found.inputs().len(), 2 * n);
+            assert_eq!(found.groups(0).next().unwrap().pixels.len(), 2 * (n - 1));
+        }
+        assert!(bar.identify(&Picture::from_ascii("Fiii\n.ii.\n", &legend).unwrap()).is_none());
+        let strict = Fsm::from_ascii("Fikki\n.ikki\n", &legend).unwrap().strict_border().build().unwrap();
+        assert!(strict.identify(&Picture::from_ascii(".....\nFiiii\n.iiii\n.....\n", &legend).unwrap()).is_some());
+        assert!(strict.identify(&Picture::from_ascii(".....\nFiiii\n.iiii\n...k.\n", &legend).unwrap()).is_none());
+
+        // Two colors taking turns, the tile is two pixels long
+        let stripes = build("Fiokkio");
+        for n in 1..6 {
+            let p = Picture::from_ascii(&format!("F{}", "io".repeat(n)), &legend).unwrap();
+            assert_eq!(stripes.identify(&p).unwrap().count(0), Some(n - 1));
+            let p = Picture::from_ascii(&format!("F{}i", "io".repeat(n)), &legend).unwrap();
+            assert!(stripes.identify(&p).is_none());
+        }
+
+        // Ladder going down, rails every row and a rung every other row
+        let ladder = build("
+            .F.
+            i.i
+            iii
+            kkk
+            kkk
+            i.i
+            iii
+        ");
+        for n in 1..5 {
+            let p = Picture::from_ascii(&format!(".F.\n{}", "i.i\niii\n".repeat(n)), &legend).unwrap();
+            let found = ladder.identify(&p).unwrap();
+            assert_eq!(found.count(0), Some(n - 1));
+            assert_eq!(found.inputs().len(), 5 * n);
+        }
+        let missing_rung = Picture::from_ascii(".F.\ni.i\niii\ni.i\ni.i\n", &legend).unwrap();
+        assert!(ladder.identify(&missing_rung).is_none());
+
+        // Nothing after the marker that matches what's before it
+        let bad = Fsm::from_ascii("Fiokkii", &legend).unwrap().build();
+        assert!(matches!(bad, Err(Error::MalformedLoop{..})));
+    }
+
+    #[test]
+    /// Checks that a union matches whatever the first alternative that matches would, and says which one it was
+    fn union_reports_alternative() {
+        let legend = legend();
+        let across = build("Fio", &legend);
+        let down = build("F\ni\no", &legend);
+        let long = build("Fikkio", &legend);
+        let union = Fsm::union(&[across.clone(), down.clone()]).unwrap();
+
+        let p = Picture::from_ascii("Fio", &legend).unwrap();
+        assert_eq!(union.identify(&p).unwrap().alternative, 0);
+        let p = Picture::from_ascii("F\ni\no", &legend).unwrap();
+        let found = union.identify(&p).unwrap();
+        assert_eq!(found.alternative, 1);
+        assert_eq!(found.outputs(), vec![Point::from(0, 2)]);
+        assert!(union.identify(&Picture::from_ascii("Foi", &legend).unwrap()).is_none());
+
+        // First alternative that matches wins, alternatives of a union inside count on their own
+        let p = Picture::from_ascii("Fiiio", &legend).unwrap();
+        let nested = Fsm::union(&[union.clone(), long]).unwrap();
+        let found = nested.identify(&p).unwrap();
+        assert_eq!(found.alternative, 2);
+        assert_eq!(found.count(0), Some(2));
+        assert_eq!(Fsm::union(&[across.clone(), build("Fi", &legend)]).unwrap().identify(&Picture::from_ascii("Fio", &legend).unwrap()).unwrap().alternative, 0);
+        assert_eq!(Fsm::union(&[build("Fi", &legend), across.clone()]).unwrap().identify(&Picture::from_ascii("Fio", &legend).unwrap()).unwrap().alternative, 0);
+
+        // Alternatives survive saving
+        let loaded = Fsm::from_bytes(&nested.to_bytes()).unwrap();
+        assert_eq!(loaded.identify(&p).unwrap().alternative, 2);
+        let loaded = Fsm::from_text(&nested.to_text()).unwrap();
+        assert_eq!(loaded.alternatives, nested.alternatives);
+
+        assert!(matches!(Fsm::union(&[]), Err(Error::Union{..})));
+        let mut green = Legend::new();
+        green.add_function('F', GREEN).add_input('i', YELLOW);
+        let other = build("Fi", &green);
+        assert!(matches!(Fsm::union(&[across.clone(), other]), Err(Error::Union{..})));
+        let turned = Fsm::from_ascii("Fio", &legend).unwrap().any_orientation().build().unwrap();
+        assert!(matches!(Fsm::union(&[across.clone(), down.clone(), turned]), Err(Error::Union{..})));
+        let mut broken = across;
+        broken.states[0].t.push((99, Transition::Epsilon));
+        assert!(matches!(Fsm::union(&[down, broken]), Err(Error::Invalid{..})));
+    }
+
+    #[test]
+    /// Checks that the same glyph drawn differently builds the same fsm, equal and hashing the same
+    fn canonical_equality() {
+        let legend = legend();
+        let hash = |f: &Fsm| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            f.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        // Same glyphs drawn further in with more room around them
+        let plus = build(".i.\nFoi\n.i.\n", &legend);
+        let moved = build(".......\n.......\n....i..\n...Foi.\n....i..\n.......\n", &legend);
+        assert_eq!(plus, moved);
+        assert_eq!(hash(&plus), hash(&moved));
+        let bar = build("Fikkio\n", &legend);
+        assert_eq!(bar, build("..........\n..Fikkio..\n", &legend));
+        assert_ne!(plus, bar);
+        assert_ne!(plus, Fsm::from_ascii(".i.\nFoi\n.i.\n", &legend).unwrap().any_orientation().build().unwrap());
+
+        // Numbering the states differently doesn't matter once it's canonical
+        let n = bar.states.len();
+        let shuffle = |i: usize| if i == 0 {0} else {n - i};
+        let mut shuffled = bar.clone();
+        shuffled.states = (0..n).map(|i| {
+            let t = bar.states[shuffle(i)].t.iter().map(|(destination, transition)| {
+                let transition = match transition {
+                    Transition::MoveRelative(rel_state, p) => Transition::MoveRelative(shuffle(*rel_state), *p),
+                    other => other.clone(),
+                };
+                (shuffle(*destination), transition)
+            }).collect();
+            State{t}
+        }).collect();
+        assert_ne!(shuffled, bar);
+        assert_eq!(shuffled.canonical().unwrap(), bar);
+        let p = Picture::from_ascii("Fiiio", &legend).unwrap();
+        assert_eq!(shuffled.identify(&p), bar.identify(&p));
+
+        // Duplicates in a library of definitions
+        let library: HashSet<Fsm> = [plus.clone(), moved, bar.clone(), shuffled.canonical().unwrap(), Fsm::union(&[plus.clone(), bar.clone()]).unwrap()].into_iter().collect();
+        assert_eq!(library.len(), 3);
+
+        // States only moves get to come out in the same order every time
+        let mut spare = bar.clone();
+        let first = spare.states.len();
+        for i in 0..8 {
+            spare.states.push(State{t: vec![(first - 1, Transition::MoveRelative(first + i, Point::from(i as i32, 0)))]});
+        }
+        let canonical = spare.canonical().unwrap();
+        assert!((0..10).all(|_| spare.canonical().unwrap() == canonical && hash(&spare.canonical().unwrap()) == hash(&canonical)));
+
+        // Nothing to number if it doesn't make sense
+        let mut broken = bar.clone();
+        broken.states[0].t.push((99, Transition::Epsilon));
+        assert!(matches!(broken.canonical(), Err(Error::Invalid{..})));
+    }
+
+    #[test]
+    /// Checks that matching gives up when it goes over a limit, and finds the same as without limits when it doesn't
+    fn limits_give_up() {
+        let legend = legend();
+        let bar = build("Fikkio", &legend);
+        let long = Picture::from_ascii(&format!("F{}o", "i".repeat(200)), &legend).unwrap();
+        let unclosed = Picture::from_ascii(&format!("F{}", "i".repeat(200)), &legend).unwrap();
+
+        // Enough of everything is the same as no limits
+        let plenty = Limits{steps: Some(100_000), depth: Some(100), deadline: Some(std::time::Instant::now() + std::time::Duration::from_secs(60))};
+        assert_eq!(bar.identify_within(&long, plenty), Outcome::Found(bar.identify(&long).unwrap()));
+        assert_eq!(bar.identify_within(&unclosed, plenty), Outcome::NotFound);
+        assert_eq!(bar.identify_within(&unclosed, Limits::default()), Outcome::NotFound);
+
+        let steps = Limits{steps: Some(50), ..Limits::default()};
+        assert_eq!(bar.identify_within(&long, steps), Outcome::GaveUp(Limit::Steps));
+        assert_eq!(bar.identify_within(&unclosed, steps), Outcome::GaveUp(Limit::Steps));
+        let depth = Limits{depth: Some(2), ..Limits::default()};
+        assert_eq!(bar.identify_within(&long, depth), Outcome::GaveUp(Limit::Depth));
+        let late = Limits{deadline: Some(std::time::Instant::now()), ..Limits::default()};
+        assert_eq!(bar.identify_within(&long, late), Outcome::GaveUp(Limit::Deadline));
+        assert!(bar.identify_within(&long, late).found().is_none());
+
+        // Anchored the same way
+        let head = Point::zero();
+        assert_eq!(bar.identify_at_within(&long, head, plenty), Outcome::Found(bar.identify_at(&long, head).unwrap()));
+        assert_eq!(bar.identify_at_within(&long, head, steps), Outcome::GaveUp(Limit::Steps));
+        assert_eq!(bar.identify_at_within(&long, head, depth), Outcome::GaveUp(Limit::Depth));
+        assert_eq!(bar.identify_at_within(&long, head, late), Outcome::GaveUp(Limit::Deadline));
+        assert_eq!(bar.identify_at_within(&long, Point::from(1, 0), steps), Outcome::NotFound);
+
+        // Searching a whole picture shares one budget
+        let two = Picture::from_ascii("Fiiio\nFiiio\n", &legend).unwrap();
+        let mut all = bar.find_iter(&two);
+        assert_eq!(all.by_ref().count(), 2);
+        assert_eq!(all.gave_up(), None);
+        let mut cut_short = bar.find_iter(&two).within(Limits{steps: Some(40), ..Limits::default()});
+        assert!(cut_short.by_ref().count() < 2);
+        assert_eq!(cut_short.gave_up(), Some(Limit::Steps));
+    }
+
+    #[test]
+    /// Checks that loops inside loops each go round on their own, with the inner captures under the outer one
+    fn nested_loops() {
+        let mut legend = Legend::new();
+        legend.add_function('F', BLUE).add_input('i', YELLOW).add('k', BLACK).add('m', Color::from(1, 0, 0));
+        // Rows of any width, as many rows as you like, every row as wide as the rest
+        let grid = Fsm::from_ascii("
+            .F....
+            .immi.
+            .kkkk.
+            .kkkk.
+            .immi.
+        ", &legend).unwrap().exact_component().build().unwrap();
+
+        for width in 1..5 {
+            for height in 1..5 {
+                let rows = format!(".{}.\n", "i".repeat(width)).repeat(height);
+                let p = Picture::from_ascii(&format!(".F{}\n{}", ".".repeat(width), rows), &legend).unwrap();
+                let found = grid.identify(&p).unwrap();
+                assert_eq!(found.inputs().len(), width * height);
+                assert_eq!(found.count(0), Some(height - 1));
+                assert_eq!(found.groups(1).count(), height);
+                assert!(found.groups(1).all(|c| c.count == width - 1));
+
+                // Every row after the first goes round inside the loop of rows
+                let outer = found.captures.iter().find(|c| c.group == 0).unwrap();
+                assert_eq!(outer.children.len(), height - 1);
+                assert!(outer.children.iter().all(|c| c.group == 1 && c.children.is_empty()));
+                assert_eq!(found.all_captures().len(), height + 1);
+            }
+        }
+        let ragged = Picture::from_ascii(".F...\n.iii.\n.ii..\n", &legend).unwrap();
+        assert!(grid.identify(&ragged).is_none());
+    }
+
+    #[test]
+    /// Checks that very long lines and long runs of free transitions match without running out of stack
+    fn long_lines() {
+        // Test threads have a smaller stack than main, if these fit there they fit anywhere
+        let legend = legend();
+        let long = Picture::from_ascii(&format!("F{}o", "i".repeat(10_000)), &legend).unwrap();
+
+        let bar = build("Fikkio", &legend);
+        assert_eq!(bar.identify(&long).unwrap().count(0), Some(9_999));
+
+        // Every pixel its own state, that's plenty deep to have blown the stack and still quick to match
+        let line = Picture::from_ascii(&format!("F{}o", "i".repeat(2_000)), &legend).unwrap();
+        let drawn = build(&format!("F{}o", "i".repeat(2_000)), &legend);
+        assert_eq!(drawn.identify(&line).unwrap().inputs().len(), 2_000);
+
+        // Nothing but free transitions between the ends
+        let moves = Pattern::Seq(vec![Pattern::Move(Point::from(1, 0)); 2_001]);
+        let jump = Fsm::from_pattern(BLUE, Pattern::Seq(vec![Pattern::Consume(BLUE), moves, Pattern::Consume(RED)])).add_output(RED).build().unwrap();
+        assert!(jump.identify(&line).is_some());
+    }
 }
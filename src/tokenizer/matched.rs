@@ -0,0 +1,181 @@
+#![allow(dead_code)]
+use crate::tokenizer::point::*;
+use crate::tokenizer::picture::*;
+use crate::tokenizer::fsm::ColorType;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// One run of a capture group, a loop in the definition gives one of these per place it matched
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub group: u8,
+    // How many times the group went round, a loop over a tile goes round once per copy of the tile
+    pub count: usize,
+    // Pixels consumed while the group was open, in order
+    pub pixels: Vec<Point>,
+    // Groups that opened and closed while this one was open, in the order they closed
+    pub children: Vec<Capture>,
+}
+
+impl Capture {
+    /// This capture and everything inside it, innermost first in the order they closed
+    pub fn flatten(&self) -> Vec<&Capture> {
+        let mut all: Vec<&Capture> = self.children.iter().flat_map(|c| c.flatten()).collect();
+        all.push(self);
+        return all;
+    }
+}
+
+/// What can cut a match attempt short
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps, // Went through more states than allowed
+    Depth, // Followed more free transitions one after another than allowed
+    Deadline, // Still going at the deadline
+}
+
+/// How much matching is allowed to do before giving up, None means no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    // States gone through, counted across every anchor and orientation tried
+    pub steps: Option<usize>,
+    // Free transitions followed one after another without consuming anything
+    pub depth: Option<usize>,
+    // When to stop, the clock is only looked at every so often so it can run a little past it
+    pub deadline: Option<Instant>,
+}
+
+/// How a match attempt with limits went
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Found(Match),
+    NotFound,
+    GaveUp(Limit), // Hit a limit before it could tell, there might still be a match
+}
+
+impl Outcome {
+    /// The match if there was one, giving up counts as not finding one
+    pub fn found(self) -> Option<Match> {
+        match self {
+            Outcome::Found(found) => Some(found),
+            Outcome::NotFound | Outcome::GaveUp(_) => None,
+        }
+    }
+}
+
+/// One spot in a picture where an fsm matched
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    // Function pixel the match started from
+    pub anchor: Point,
+    // Which way the glyph was turned or flipped
+    pub orientation: Orientation,
+    // Every pixel consumed in the order it was consumed, with the definition color that consumed it
+    pub pixels: Vec<(Color, Point)>,
+    // What each definition color is for
+    pub colors: HashMap<Color, ColorType>,
+    // Every capture group that closed outside of any other, in the order they closed
+    // The ones inside are their children
+    pub captures: Vec<Capture>,
+    // Top left and bottom right corners of the consumed pixels, inclusive
+    pub bounds: (Point, Point),
+    // Picture color each variable in the definition bound to
+    pub bindings: HashMap<Color, Color>,
+    // Which alternative of a union matched, always 0 for an fsm built from one picture
+    pub alternative: usize,
+}
+
+impl Match {
+    pub fn new(anchor: Point, orientation: Orientation, pixels: Vec<(Color, Point)>, colors: HashMap<Color, ColorType>, captures: Vec<Capture>, bindings: HashMap<Color, Color>, alternative: usize) -> Match {
+        let mut min = anchor;
+        let mut max = anchor;
+        for (_, p) in pixels.iter() {
+            min = Point::from(min.x.min(p.x), min.y.min(p.y));
+            max = Point::from(max.x.max(p.x), max.y.max(p.y));
+        }
+        return Match{anchor, orientation, pixels, colors, captures, bounds: (min, max), bindings, alternative};
+    }
+
+    /// Iterates over every pixel consumed by the match
+    pub fn consumed(&self) -> impl Iterator<Item = &Point> {
+        return self.pixels.iter().map(|(_, p)| p);
+    }
+
+    /// Every capture at any depth, in the order they closed
+    pub fn all_captures(&self) -> Vec<&Capture> {
+        return self.captures.iter().flat_map(|c| c.flatten()).collect();
+    }
+
+    /// Every run of a capture group at any depth, in the order they closed
+    pub fn groups(&self, g: u8) -> impl Iterator<Item = &Capture> {
+        return self.all_captures().into_iter().filter(move |c| c.group == g);
+    }
+
+    /// How many times a capture group went round, runs of the same group always agree
+    pub fn count(&self, g: u8) -> Option<usize> {
+        return self.groups(g).next().map(|c| c.count);
+    }
+
+    /// Every pixel consumed by a definition color, in order
+    pub fn of_color(&self, c: Color) -> Vec<Point> {
+        return self.pixels.iter().filter(|(color, _)| *color == c).map(|(_, p)| *p).collect();
+    }
+
+    /// Every pixel consumed by a color used for t, in order
+    pub fn of_type(&self, t: ColorType) -> Vec<Point> {
+        return self.pixels.iter().filter(|(color, _)| self.colors.get(color) == Some(&t)).map(|(_, p)| *p).collect();
+    }
+
+    /// Every pixel consumed as an input
+    pub fn inputs(&self) -> Vec<Point> {
+        return self.of_type(ColorType::Input);
+    }
+
+    /// Every pixel consumed as an output
+    pub fn outputs(&self) -> Vec<Point> {
+        return self.of_type(ColorType::Output);
+    }
+
+    /// Every pixel consumed as part of the function itself
+    pub fn function(&self) -> Vec<Point> {
+        return self.of_type(ColorType::Function);
+    }
+
+    /// Every pixel consumed as a variable
+    pub fn variables(&self) -> Vec<Point> {
+        return self.of_type(ColorType::Variable);
+    }
+
+    /// The picture color a variable bound to, None if it isn't a variable or never consumed anything
+    pub fn binding(&self, variable: Color) -> Option<Color> {
+        return self.bindings.get(&variable).copied();
+    }
+
+    /// Pixels grouped by the definition color that consumed them
+    pub fn by_color(&self) -> HashMap<Color, Vec<Point>> {
+        let mut collect: HashMap<Color, Vec<Point>> = HashMap::new();
+        self.colors.keys().for_each(|k| {
+            collect.insert(*k, vec![]);
+        });
+        for (color, p) in self.pixels.iter() {
+            collect.entry(*color).or_default().push(*p);
+        }
+        return collect;
+    }
+
+    /// Cuts the matched region out of the picture it was found in
+    /// Anything inside the bounds that the match didn't consume is left white
+    pub fn picture(&self, p: &Picture) -> Picture {
+        let (min, max) = self.bounds;
+        let mut new_picture = Picture{
+            pixels: vec![WHITE; ((max.x - min.x + 1) * (max.y - min.y + 1)) as usize],
+            width: max.x - min.x + 1,
+            height: max.y - min.y + 1,
+            path: p.path.clone(),
+        };
+        for point in self.consumed() {
+            new_picture.set_point(*point - min, p.get_point(*point));
+        }
+        return new_picture;
+    }
+}
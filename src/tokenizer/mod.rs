@@ -1,4 +1,15 @@
+pub mod ascii;
+pub mod dot;
+pub mod error;
 pub mod fsm;
+pub mod matched;
+pub mod optimize;
+pub mod pattern;
 pub mod picture;
 pub mod point;
+pub mod predicate;
+pub mod serialize;
+#[allow(clippy::module_inception)]
 pub mod tokenizer;
+pub mod validate;
+pub mod vm;
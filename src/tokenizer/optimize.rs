@@ -0,0 +1,301 @@
+#![allow(dead_code)]
+use std::collections::HashSet;
+use crate::tokenizer::fsm::{Fsm, State, Transition};
+use crate::tokenizer::error::Error;
+
+// Taking out states that only pass threads along, so matching has less to step through
+// Every pass keeps matches exactly the same, including which way through wins. The one thing to watch
+// out for is that an epsilon never goes straight back to the state a thread got to it from for free,
+// so nothing is moved in a way that changes where a thread came from when that could matter.
+
+impl Fsm {
+    /// Copy of the fsm that matches exactly the same things, with fewer states and epsilons to go through
+    /// Errors if the fsm isn't valid, there's no telling what an invalid one matches
+    pub fn optimize(&self) -> Result<Fsm, Error> {
+        self.validate()?;
+        let mut f = self.clone();
+        f.drop_unreachable();
+        loop {
+            let mut changed = f.tidy();
+            changed |= f.skip_pass_throughs();
+            changed |= f.inline_epsilons();
+            changed |= f.merge_chains();
+            f.drop_unreachable();
+            if !changed {break}
+        }
+        return Ok(f);
+    }
+
+    /// Every state something moves relative to
+    fn relatives(&self) -> HashSet<usize> {
+        return self.states.iter().flat_map(|state| state.t.iter()).filter_map(|(_, transition)| match transition {
+            Transition::MoveRelative(rel_state, _) => Some(*rel_state),
+            _ => None,
+        }).collect();
+    }
+
+    /// Which states go to each state, once per transition
+    fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut preds = vec![vec![]; self.states.len()];
+        for (i, state) in self.states.iter().enumerate() {
+            for (destination, _) in state.t.iter() {
+                preds[*destination].push(i);
+            }
+        }
+        return preds;
+    }
+
+    /// Takes out epsilons back to the same state and transitions that are exactly the same as an earlier one
+    /// Either way whatever they lead to was already tried
+    fn tidy(&mut self) -> bool {
+        let mut changed = false;
+        for (i, state) in self.states.iter_mut().enumerate() {
+            let before = state.t.len();
+            let mut kept: Vec<(usize, Transition)> = vec![];
+            for (destination, transition) in state.t.drain(..) {
+                if (destination == i && transition == Transition::Epsilon) || kept.contains(&(destination, transition.clone())) {continue}
+                kept.push((destination, transition));
+            }
+            changed |= kept.len() != before;
+            state.t = kept;
+        }
+        return changed;
+    }
+
+    /// States that do nothing but epsilon somewhere else get skipped, everything going to them goes there instead
+    fn skip_pass_throughs(&mut self) -> bool {
+        let relatives = self.relatives();
+        let mut preds = self.predecessors();
+        let mut changed = false;
+        for s in 1..self.states.len() {
+            // Moves need the head remembered there, and alternatives need to keep where they start
+            if relatives.contains(&s) || self.alternatives.contains(&s) {continue}
+            let [(d, Transition::Epsilon)] = self.states[s].t.as_slice() else {continue};
+            let d = *d;
+            if d == s {continue}
+            // Bouncing back off d would go differently once s isn't in between
+            if self.states[d].t.iter().any(|(x, t)| *t == Transition::Epsilon && (*x == s || preds[s].contains(x))) {continue}
+
+            let from = std::mem::take(&mut preds[s]);
+            for p in from.iter() {
+                for (destination, _) in self.states[*p].t.iter_mut() {
+                    if *destination == s {
+                        *destination = d;
+                    }
+                }
+            }
+            preds[d].extend(from);
+            changed = true;
+        }
+        return changed;
+    }
+
+    /// An epsilon gets swapped for the transitions of the state it goes to, in the same spot so they're tried
+    /// in the same order, as long as that state's own epsilons only go back where the epsilon came from
+    /// Those would bounce straight back so they're left out, and since nothing new to inline comes in it stops
+    fn inline_epsilons(&mut self) -> bool {
+        let relatives = self.relatives();
+        let mut changed = false;
+        for p in 0..self.states.len() {
+            let mut i = 0;
+            while i < self.states[p].t.len() {
+                let (d, transition) = &self.states[p].t[i];
+                let d = *d;
+                if *transition != Transition::Epsilon || !self.inlinable(p, d, &relatives) {
+                    i += 1;
+                    continue;
+                }
+                let inlined: Vec<(usize, Transition)> = self.states[d].t.iter()
+                    .filter(|(x, t)| !(*x == p && *t == Transition::Epsilon)).cloned().collect();
+                let count = inlined.len();
+                self.states[p].t.splice(i..i + 1, inlined);
+                i += count;
+                changed = true;
+            }
+        }
+        return changed;
+    }
+
+    /// Whether state p's epsilon to d can be swapped for d's transitions without changing any match
+    fn inlinable(&self, p: usize, d: usize, relatives: &HashSet<usize>) -> bool {
+        // Finish states and ones moved relative to have to be gone through
+        if d == p || self.states[d].t.is_empty() || relatives.contains(&d) {return false}
+        if self.alternative_of(p) != self.alternative_of(d) {return false}
+        return self.states[d].t.iter().all(|(x, t)| match t {
+            Transition::Epsilon => *x == p,
+            // Threads leaving for free remember where they left from, p instead of d now
+            Transition::Capture(_) | Transition::EndCapture(_) | Transition::Round(_) | Transition::NotAt(..) => {
+                !self.states[*x].t.iter().any(|(y, t)| *t == Transition::Epsilon && (*y == d || *y == p))
+            }
+            Transition::Consume(..) | Transition::MoveRelative(..) => true,
+        });
+    }
+
+    /// A state that only epsilons to a state nothing else goes to takes over that state's transitions
+    fn merge_chains(&mut self) -> bool {
+        let mut preds = self.predecessors();
+        let mut changed = false;
+        for p in 0..self.states.len() {
+            let [(s, Transition::Epsilon)] = self.states[p].t.as_slice() else {continue};
+            let s = *s;
+            if s == p || s == 0 || preds[s].len() != 1 || self.alternatives.contains(&s) {continue}
+            if self.alternative_of(p) != self.alternative_of(s) {continue}
+            // Threads leaving s would have come from somewhere else, which changes where they can't bounce back to
+            if self.states[s].t.iter().any(|(x, t)| *t == Transition::Epsilon && preds[p].contains(x)) {continue}
+
+            let t = std::mem::take(&mut self.states[s].t);
+            for (destination, _) in t.iter() {
+                for pred in preds[*destination].iter_mut() {
+                    if *pred == s {
+                        *pred = p;
+                    }
+                }
+            }
+            self.states[p].t = t;
+            preds[s].clear();
+            // Something entering s always entered p right before with the head in the same place
+            for state in self.states.iter_mut() {
+                for (_, transition) in state.t.iter_mut() {
+                    if let Transition::MoveRelative(rel_state, _) = transition {
+                        if *rel_state == s {
+                            *rel_state = p;
+                        }
+                    }
+                }
+            }
+            changed = true;
+        }
+        return changed;
+    }
+
+    /// Same fsm with its states numbered in the order a walk from the start finds them, first transition first
+    /// and one alternative after another, leaving out anything the walk never gets to
+    /// Fsms that only differ in how their states are numbered have the same canonical form
+    /// Errors if the fsm isn't valid
+    pub fn canonical(&self) -> Result<Fsm, Error> {
+        self.validate()?;
+        let mut order = vec![];
+        let mut visited = vec![false; self.states.len()];
+        let mut roots = vec![0];
+        roots.extend(self.alternatives.iter().copied());
+        // Sorted so states only a move gets to are numbered the same every time
+        let mut relatives: Vec<usize> = self.relatives().into_iter().collect();
+        relatives.sort();
+        roots.extend(relatives);
+        for root in roots {
+            let mut stack = vec![root];
+            while let Some(state) = stack.pop() {
+                if visited[state] {continue}
+                visited[state] = true;
+                order.push(state);
+                // Backwards so the first transition comes off the stack first
+                stack.extend(self.states[state].t.iter().rev().map(|(destination, _)| *destination).filter(|d| !visited[*d]));
+            }
+        }
+        let mut f = self.clone();
+        f.renumber(&order);
+        return Ok(f);
+    }
+
+    /// Takes out every state nothing can get to, the rest keep their order
+    fn drop_unreachable(&mut self) {
+        let mut reachable = vec![false; self.states.len()];
+        let mut stack: Vec<usize> = vec![0];
+        stack.extend(self.alternatives.iter().copied());
+        stack.extend(self.relatives());
+        while let Some(state) = stack.pop() {
+            if state >= reachable.len() || reachable[state] {continue}
+            reachable[state] = true;
+            stack.extend(self.states[state].t.iter().map(|(destination, _)| *destination));
+        }
+        if reachable.iter().all(|r| *r) {return}
+        let order: Vec<usize> = (0..self.states.len()).filter(|i| reachable[*i]).collect();
+        self.renumber(&order);
+    }
+
+    /// Keeps only the states in order, numbered the way they come in it
+    /// Everything pointing at a kept state has to be kept too
+    fn renumber(&mut self, order: &[usize]) {
+        let mut renumber = vec![usize::MAX; self.states.len()];
+        for (i, state) in order.iter().enumerate() {
+            renumber[*state] = i;
+        }
+
+        let mut states = std::mem::take(&mut self.states);
+        for old in order.iter() {
+            let mut state = std::mem::replace(&mut states[*old], State::new());
+            for (destination, transition) in state.t.iter_mut() {
+                *destination = renumber[*destination];
+                if let Transition::MoveRelative(rel_state, _) = transition {
+                    *rel_state = renumber[*rel_state];
+                }
+            }
+            self.states.push(state);
+        }
+        for a in self.alternatives.iter_mut() {
+            *a = renumber[*a];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::fsm::{LOOP_MUST, LOOP_MAY};
+    use crate::tokenizer::fsm::tests::{build, definitions, legend};
+    use crate::tokenizer::pattern::Pattern;
+    use crate::tokenizer::picture::*;
+    use crate::tokenizer::point::Point;
+    use super::*;
+
+    #[test]
+    /// Checks optimizing doesn't change what anything in the definitions matches, and takes states out
+    fn optimize_keeps_matches() {
+        let mut pictures = definitions();
+        let mut fsms = vec![];
+        for p in pictures.iter() {
+            fsms.push(Fsm::builder(p).unwrap().build().unwrap());
+            fsms.push(Fsm::builder(p).unwrap().any_orientation().build().unwrap());
+        }
+        // Loops of every kind and alternatives too
+        let mut legend = legend();
+        legend.add('m', Color::from(1, 0, 0));
+        let grid = build(".F....\n.immi.\n.kkkk.\n.kkkk.\n.immi.\n", &legend);
+        let mut markers = legend.clone();
+        markers.add('M', Color::from(0, 0, LOOP_MUST)).add('Q', Color::from(0, 0, LOOP_MAY));
+        let bounded = build("FiMQi", &markers);
+        let union = Fsm::union(&[build("Fio", &legend), build("Fikkio", &legend)]).unwrap();
+        let pattern = Fsm::from_pattern(BLUE, Pattern::Seq(vec![
+            Pattern::Consume(BLUE),
+            Pattern::Alt(vec![
+                Pattern::Seq(vec![Pattern::Move(Point::from(1, 0)), Pattern::Consume(RED)]),
+                Pattern::repeat(0, 1, None, Pattern::Seq(vec![Pattern::Move(Point::from(1, 0)), Pattern::Consume(YELLOW)])),
+            ]),
+            Pattern::optional(Pattern::Seq(vec![Pattern::Move(Point::from(1, 0)), Pattern::Consume(RED)])),
+        ])).add_input(YELLOW).add_output(RED).build().unwrap();
+        fsms.extend([grid, bounded, union, pattern]);
+        for grid in [".F..\n.ii.\n.ii.\n", "Fiii", "Fio", "Fiiio", "Fo", "Fiio\n....\nFo..\nFiii\n"] {
+            pictures.push(Picture::from_ascii(grid, &legend).unwrap());
+        }
+
+        let epsilons = |f: &Fsm| f.states.iter().flat_map(|state| state.t.iter()).filter(|(_, t)| *t == Transition::Epsilon).count();
+        let mut before = (0, 0);
+        let mut after = (0, 0);
+        for fsm in fsms.iter() {
+            let optimized = fsm.optimize().unwrap();
+            assert!(optimized.validate().is_ok());
+            assert!(optimized.states.len() <= fsm.states.len());
+            before = (before.0 + fsm.states.len(), before.1 + epsilons(fsm));
+            after = (after.0 + optimized.states.len(), after.1 + epsilons(&optimized));
+            for p in pictures.iter() {
+                assert_eq!(optimized.identify(p), fsm.identify(p));
+                assert_eq!(optimized.find_all(p), fsm.find_all(p));
+            }
+        }
+        assert!(after.0 < before.0 && after.1 < before.1);
+
+        // Nothing to optimize if it doesn't make sense
+        let mut broken = fsms[0].clone();
+        broken.states[0].t.push((99, Transition::Epsilon));
+        assert!(matches!(broken.optimize(), Err(Error::Invalid{..})));
+    }
+}
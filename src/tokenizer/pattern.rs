@@ -0,0 +1,285 @@
+#![allow(dead_code)]
+use crate::tokenizer::point::*;
+use crate::tokenizer::picture::*;
+use crate::tokenizer::fsm::{Fsm, FSMBuilder, State, Transition, ColorType};
+use crate::tokenizer::predicate::ColorPredicate;
+use crate::tokenizer::error::Error;
+use std::collections::HashMap;
+
+// Building fsms in code instead of drawing them
+// A pattern is a tree of pieces that say what the head does, starting on the function pixel a match is
+// anchored on, so every way through it has to start by consuming the function color. Moves are relative to wherever the head is and get turned with the glyph like drawn ones.
+//
+//     // Function pixel, then a row of at least one input going right
+//     let row = Pattern::Seq(vec![
+//         Pattern::Consume(BLUE),
+//         Pattern::repeat(0, 1, None, Pattern::Seq(vec![Pattern::Move(Point::from(1, 0)), Pattern::Consume(YELLOW)])),
+//     ]);
+//     let fsm = Fsm::from_pattern(BLUE, row).add_input(YELLOW).build()?;
+
+/// A piece of a glyph described in code, see Fsm::from_pattern
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Consume(Color), // Takes the pixel under the head as definition color x
+    Move(Point), // Moves the head by p
+    Seq(Vec<Pattern>), // Every pattern one after the other
+    Optional(Box<Pattern>), // The pattern if it matches, otherwise nothing
+    Repeat{group: u8, min: usize, max: Option<usize>, body: Box<Pattern>}, // Body over and over, captured and counted as group
+    Alt(Vec<Pattern>), // The first of the patterns that matches
+    Capture(u8, Box<Pattern>), // Whatever the pattern takes goes in capture group x
+    Branch(Box<Pattern>), // The pattern, then the head goes back to where it was before it
+}
+
+impl Pattern {
+    /// The pattern if it matches, otherwise nothing
+    pub fn optional(p: Pattern) -> Pattern {
+        return Pattern::Optional(Box::new(p));
+    }
+
+    /// The pattern at least min and at most max times, None goes round as many times as it likes
+    /// Every go round starts wherever the last one left the head, it's captured as group and counted in rounds
+    pub fn repeat(group: u8, min: usize, max: Option<usize>, body: Pattern) -> Pattern {
+        return Pattern::Repeat{group, min, max, body: Box::new(body)};
+    }
+
+    /// Whatever the pattern takes goes in capture group g
+    pub fn capture(g: u8, p: Pattern) -> Pattern {
+        return Pattern::Capture(g, Box::new(p));
+    }
+
+    /// The pattern, then the head goes back to where it was, for glyphs that split into arms
+    pub fn branch(p: Pattern) -> Pattern {
+        return Pattern::Branch(Box::new(p));
+    }
+
+    /// Whether every way through the pattern takes at least one pixel
+    pub fn always_consumes(&self) -> bool {
+        match self {
+            Pattern::Consume(_) => true,
+            Pattern::Move(_) | Pattern::Optional(_) => false,
+            Pattern::Seq(patterns) => patterns.iter().any(|p| p.always_consumes()),
+            Pattern::Repeat{min, body, ..} => *min > 0 && body.always_consumes(),
+            Pattern::Alt(patterns) => patterns.iter().all(|p| p.always_consumes()),
+            Pattern::Capture(_, p) | Pattern::Branch(p) => p.always_consumes(),
+        }
+    }
+
+    /// Whether every way through the pattern that does anything starts by taking c, and whether
+    /// there's a way through that does nothing at all
+    fn starts_with(&self, c: Color) -> (bool, bool) {
+        match self {
+            Pattern::Consume(x) => (*x == c, false),
+            Pattern::Move(_) => (false, false),
+            Pattern::Seq(patterns) => {
+                // Later pieces only go first if everything before them can do nothing
+                let mut starts = true;
+                for p in patterns {
+                    let (first, empty) = p.starts_with(c);
+                    starts &= first;
+                    if !empty {return (starts, false)}
+                }
+                (starts, true)
+            }
+            Pattern::Optional(p) => (p.starts_with(c).0, true),
+            Pattern::Repeat{min, body, ..} => {
+                let (first, empty) = body.starts_with(c);
+                (first, empty || *min == 0)
+            }
+            Pattern::Alt(patterns) => {
+                let starts = patterns.iter().map(|p| p.starts_with(c));
+                starts.fold((true, false), |(all, any), (first, empty)| (all && first, any || empty))
+            }
+            Pattern::Capture(_, p) | Pattern::Branch(p) => p.starts_with(c),
+        }
+    }
+
+    /// Checks every color is one the builder knows about and every piece makes sense
+    fn validate(&self, colors: &HashMap<Color, (ColorType, ColorPredicate)>) -> Result<(), Error> {
+        let error = |reason: String| Err(Error::Pattern{reason});
+        match self {
+            Pattern::Consume(c) if !colors.contains_key(c) => return error(format!("{} is consumed but was never added", c.hex())),
+            Pattern::Consume(_) | Pattern::Move(_) => {}
+            Pattern::Seq(patterns) => {
+                for p in patterns {
+                    p.validate(colors)?;
+                }
+            }
+            Pattern::Alt(patterns) => {
+                if patterns.is_empty() {
+                    return error(String::from("alternation has nothing to choose from"));
+                }
+                for p in patterns {
+                    p.validate(colors)?;
+                }
+            }
+            Pattern::Repeat{group, min, max, body} => {
+                if max.is_some_and(|max| max < *min) {
+                    return error(format!("group {} has to go round at least {} times but at most {}", group, min, max.unwrap()));
+                }
+                // Going round forever without taking anything never finishes
+                if max.is_none() && !body.always_consumes() {
+                    return error(format!("group {} can go round forever so it has to consume something every time", group));
+                }
+                body.validate(colors)?;
+            }
+            Pattern::Optional(p) | Pattern::Capture(_, p) | Pattern::Branch(p) => p.validate(colors)?,
+        }
+        return Ok(());
+    }
+}
+
+impl Fsm {
+    /// Starts building an fsm out of a pattern instead of a picture
+    /// The pattern has to start by consuming func_color, building errors if it doesn't
+    /// Colors and options are added to the builder the same as for pictures, func_color is already added
+    pub fn from_pattern(func_color: Color, pattern: Pattern) -> FSMBuilder {
+        // Stands in for a definition picture, nothing walks it
+        let p = Picture{pixels: vec![func_color], width: 1, height: 1, path: None};
+        // Can't fail, the picture isn't empty and is all function color
+        let mut builder = Fsm::builder_with_function(&p, func_color).unwrap();
+        builder.pattern = Some(pattern);
+        return builder;
+    }
+}
+
+impl FSMBuilder {
+    /// Checks the pattern then turns it into states after the last one
+    pub(crate) fn compile_pattern(&mut self, pattern: &Pattern) -> Result<(), Error> {
+        if self.strict_border {
+            return Err(Error::Pattern{reason: String::from("strict border needs a definition picture")});
+        }
+        pattern.validate(&self.colors)?;
+        // Matches are anchored on a function pixel, a pattern that doesn't take it first can never match
+        let func_color = self.colors.iter().find_map(|(c, (t, _))| if *t == ColorType::Function {Some(*c)} else {None});
+        if func_color.is_none_or(|c| pattern.starts_with(c) != (true, false)) {
+            return Err(Error::Pattern{reason: String::from("pattern has to start by consuming the function color")});
+        }
+        self.compile(pattern);
+        return Ok(());
+    }
+
+    fn compile(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Consume(c) => self.consume(*c),
+            Pattern::Move(p) => self.move_rel(None, *p),
+            Pattern::Seq(patterns) => {
+                for p in patterns {
+                    self.compile(p);
+                }
+            }
+            Pattern::Optional(p) => {
+                let start = self.states.len() - 1;
+                self.compile(p);
+                // ...0[p...], n[]  ->  ...0[p..., Epsilon(n)], n[]
+                let end = self.states.len() - 1;
+                if end != start {
+                    self.states[start].t.push((end, Transition::Epsilon));
+                }
+            }
+            Pattern::Alt(patterns) => {
+                // Every alternative gets its own first state so none of them can run into another
+                let start = self.states.len() - 1;
+                let mut ends = vec![];
+                for p in patterns {
+                    let first = self.states.len();
+                    self.states[start].t.push((first, Transition::Epsilon));
+                    self.states.push(State::new());
+                    self.compile(p);
+                    ends.push(self.states.len() - 1);
+                }
+                // ...0[Epsilon(1), Epsilon(3)], 1[a...], 2[], 3[b...], 4[]  ->  2[Epsilon(5)], 4[Epsilon(5)], 5[]
+                let end = self.states.len();
+                self.states.push(State::new());
+                for state in ends {
+                    self.states[state].t.push((end, Transition::Epsilon));
+                }
+            }
+            Pattern::Repeat{group, min, max, body} => {
+                let g = *group;
+                self.repeat(g, *min, *max, &|b: &mut FSMBuilder| {
+                    b.compile(body);
+                    b.round(g);
+                });
+            }
+            Pattern::Capture(g, p) => {
+                self.start_capture(*g);
+                self.compile(p);
+                self.end_capture(*g);
+            }
+            Pattern::Branch(p) => {
+                let start = self.states.len() - 1;
+                self.compile(p);
+                self.move_rel(Some(start), Point::zero());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::fsm::tests::{build, legend};
+    use super::*;
+
+    #[test]
+    /// Checks that patterns built in code match the same as drawn definitions, and that nonsense patterns don't build
+    fn patterns_build_in_code() {
+        let legend = legend();
+        let picture = |grid: &str| Picture::from_ascii(grid, &legend).unwrap();
+        let right = |c: Color| Pattern::Seq(vec![Pattern::Move(Point::from(1, 0)), Pattern::Consume(c)]);
+        let down = |c: Color| Pattern::Seq(vec![Pattern::Move(Point::from(0, 1)), Pattern::Consume(c)]);
+        let build_pattern = |pattern: Pattern| Fsm::from_pattern(BLUE, pattern).add_input(YELLOW).add_output(RED).build();
+
+        // Same as the drawn Fikkio
+        let row = build_pattern(Pattern::Seq(vec![
+            Pattern::Consume(BLUE),
+            right(YELLOW),
+            Pattern::repeat(0, 0, None, right(YELLOW)),
+            right(RED),
+        ])).unwrap();
+        let drawn = build("Fikkio", &legend);
+        for n in 1..5 {
+            let p = picture(&format!("F{}o", "i".repeat(n)));
+            let found = row.identify(&p).unwrap();
+            assert_eq!(found.count(0), Some(n - 1));
+            assert_eq!(found.pixels, drawn.identify(&p).unwrap().pixels);
+        }
+        assert!(row.identify(&picture("Fo")).is_none());
+
+        // Output below if there is one, arm to the right and back, then a choice of which way to go
+        let glyph = build_pattern(Pattern::Seq(vec![
+            Pattern::Consume(BLUE),
+            Pattern::branch(Pattern::capture(1, right(YELLOW))),
+            Pattern::optional(down(RED)),
+            Pattern::Alt(vec![down(YELLOW), right(RED)]),
+        ])).unwrap();
+        let found = glyph.identify(&picture("Fi\no.\ni.")).unwrap();
+        assert_eq!(found.outputs(), vec![Point::from(0, 1)]);
+        assert_eq!(found.inputs(), vec![Point::from(1, 0), Point::from(0, 2)]);
+        assert_eq!(found.groups(1).next().unwrap().pixels, vec![Point::from(1, 0)]);
+        let found = glyph.identify(&picture("Fi\ni.")).unwrap();
+        assert_eq!(found.inputs(), vec![Point::from(1, 0), Point::from(0, 1)]);
+        assert!(glyph.identify(&picture("Fi\n..")).is_none());
+
+        // Between two and three inputs, turned any way
+        let bounded = Fsm::from_pattern(BLUE, Pattern::Seq(vec![Pattern::Consume(BLUE), Pattern::repeat(0, 2, Some(3), down(YELLOW))]))
+            .add_input(YELLOW).any_orientation().build().unwrap();
+        assert_eq!(bounded.identify(&picture("Fiiii")).unwrap().count(0), Some(3));
+        assert_eq!(bounded.identify(&picture("iiF")).unwrap().count(0), Some(2));
+        assert!(bounded.identify(&picture("Fi")).is_none());
+
+        // Nonsense doesn't build
+        assert!(matches!(build_pattern(Pattern::Consume(GREEN)), Err(Error::Pattern{..})));
+        assert!(matches!(build_pattern(Pattern::Alt(vec![])), Err(Error::Pattern{..})));
+        assert!(matches!(build_pattern(Pattern::repeat(0, 3, Some(2), right(YELLOW))), Err(Error::Pattern{..})));
+        assert!(matches!(build_pattern(Pattern::repeat(0, 0, None, Pattern::optional(right(YELLOW)))), Err(Error::Pattern{..})));
+        // Matches are anchored on the function pixel, so that has to come first every way through
+        assert!(matches!(build_pattern(right(YELLOW)), Err(Error::Pattern{..})));
+        assert!(matches!(build_pattern(Pattern::Consume(YELLOW)), Err(Error::Pattern{..})));
+        assert!(matches!(build_pattern(Pattern::optional(Pattern::Consume(BLUE))), Err(Error::Pattern{..})));
+        assert!(matches!(build_pattern(Pattern::Alt(vec![Pattern::Consume(BLUE), Pattern::Consume(YELLOW)])), Err(Error::Pattern{..})));
+        let first = Pattern::Seq(vec![Pattern::optional(Pattern::capture(2, Pattern::Consume(BLUE))), Pattern::Consume(BLUE)]);
+        assert!(build_pattern(Pattern::Alt(vec![Pattern::Consume(BLUE), first])).is_ok());
+        let strict = Fsm::from_pattern(BLUE, Pattern::Consume(BLUE)).strict_border().build();
+        assert!(matches!(strict, Err(Error::Pattern{..})));
+    }
+}
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use image::io::Reader;
-use crate::tokenizer::point::Point;
+use crate::tokenizer::point::{Point, Orientation, SURROUNDING};
+use crate::tokenizer::error::Error;
 
 #[derive(Debug, Clone, Copy, PartialEq, Hash, Eq)]
 pub struct Color {
@@ -17,6 +20,19 @@ impl Color {
             b,
         }
     }
+
+    /// Writes the color out like #rrggbb
+    pub fn hex(&self) -> String {
+        return format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b);
+    }
+
+    /// Reads a color written like #rrggbb
+    pub fn from_hex(s: &str) -> Option<Color> {
+        let s = s.strip_prefix('#')?;
+        if s.len() != 6 || !s.is_ascii() {return None}
+        let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).ok();
+        return Some(Color::from(channel(0)?, channel(2)?, channel(4)?));
+    }
 }
 
 
@@ -33,17 +49,24 @@ pub struct Picture {
     pub pixels: Vec<Color>,
     pub width: i32,
     pub height: i32,
+    // Where the picture was loaded from, used for error messages
+    pub path: Option<PathBuf>,
 }
 
 impl Picture {
     /// Opens image if the file path is correct, returns the image in the picture format
-    pub fn open_pic(path: &str) -> Picture {
+    pub fn open_pic<P: AsRef<Path>>(path: P) -> Result<Picture, Error> {
+        let path = path.as_ref();
         // Opens image
-        let img = Reader::open(path).unwrap().decode().unwrap().to_rgb8();
+        let reader = Reader::open(path).map_err(|source| Error::Io{path: path.to_path_buf(), source})?;
+        let img = reader.decode().map_err(|source| Error::Decode{path: path.to_path_buf(), source})?.to_rgb8();
 
         // Sets the measurements of the picture
         let width = img.width();
         let height = img.height();
+        if width == 0 || height == 0 {
+            return Err(Error::EmptyImage{path: Some(path.to_path_buf())});
+        }
 
         let data: Vec<u8>= img.into_raw();
 
@@ -51,6 +74,7 @@ impl Picture {
             pixels: vec![],
             width: width as i32,
             height: height as i32,
+            path: Some(path.to_path_buf()),
         };
 
         // Add all the pixels to the picture
@@ -60,7 +84,7 @@ impl Picture {
             ret_img.pixels.push(color);
         }
 
-        return ret_img;
+        return Ok(ret_img);
     }
 
     /// Returns whether or not a point is within the bounds of a picture
@@ -99,21 +123,65 @@ impl Picture {
             pixels: vec,
             width: (x2 - x1) + 1,
             height: (y2 - y1) + 1,
+            path: self.path.clone(),
         }
 
     }
 
     /// Rotates picture clockwise
     pub fn rotate(&self) -> Picture {
-        let mut new_picture = Picture{width: self.height, height: self.width, pixels: vec![WHITE; self.pixels.len()]};
+        let mut new_picture = Picture{width: self.height, height: self.width, pixels: vec![WHITE; self.pixels.len()], path: self.path.clone()};
         for i in 0..self.width {
             for j in 0..self.height {
-                new_picture.set(j, i, self.get(i, j));
+                new_picture.set(self.height - 1 - j, i, self.get(i, j));
             }
         }
         return new_picture;
     }
 
+    /// Mirrors picture left to right
+    pub fn mirror(&self) -> Picture {
+        let mut new_picture = self.clone();
+        for i in 0..self.width {
+            for j in 0..self.height {
+                new_picture.set(self.width - 1 - i, j, self.get(i, j));
+            }
+        }
+        return new_picture;
+    }
+
+    /// Turns and flips picture the same way the orientation turns and flips points
+    pub fn orient(&self, o: Orientation) -> Picture {
+        let mut new_picture = if o.mirrored {self.mirror()} else {self.clone()};
+        for _ in 0..(o.turns % 4) {
+            new_picture = new_picture.rotate();
+        }
+        return new_picture;
+    }
+
+    /// Every significant pixel connected to start through its surrounding pixels, leaving out anything in skip
+    /// Significant means anything but white and green
+    pub fn component(&self, start: Point, skip: &HashSet<Point>) -> HashSet<Point> {
+        let significant = |p: Point| {
+            let c = self.get_point(p);
+            c != WHITE && c != GREEN && !skip.contains(&p)
+        };
+        let mut found = HashSet::new();
+        if !self.in_bounds(start) || !significant(start) {return found}
+
+        let mut stack = vec![start];
+        found.insert(start);
+        while let Some(p) = stack.pop() {
+            for pos in SURROUNDING {
+                let next = p + pos;
+                if self.in_bounds(next) && significant(next) && found.insert(next) {
+                    stack.push(next);
+                }
+            }
+        }
+        return found;
+    }
+
     /// Returns a color if all 4 corners of the picture are the same color
     pub fn four_corners(&self) -> Option<Color> {
         let one = self.get(0,0);
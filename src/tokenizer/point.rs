@@ -70,3 +70,46 @@ pub const SURROUNDING: [Point; 8] = [
     Point{x: -1, y: -1},
     Point{x: -1, y: 1},
 ];
+
+/// One of the eight ways a glyph can be turned or flipped (the D4 group)
+/// Mirrors left to right first, then rotates clockwise a quarter turn at a time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Orientation{pub turns: u8, pub mirrored: bool}
+
+impl Orientation {
+    /// Leaves everything where it is
+    pub const IDENTITY: Orientation = Orientation{turns: 0, mirrored: false};
+
+    /// All four rotations followed by all four of their mirror images
+    pub const ALL: [Orientation; 8] = [
+        Orientation{turns: 0, mirrored: false},
+        Orientation{turns: 1, mirrored: false},
+        Orientation{turns: 2, mirrored: false},
+        Orientation{turns: 3, mirrored: false},
+        Orientation{turns: 0, mirrored: true},
+        Orientation{turns: 1, mirrored: true},
+        Orientation{turns: 2, mirrored: true},
+        Orientation{turns: 3, mirrored: true},
+    ];
+
+    /// Turns or flips an offset, y grows downwards so clockwise takes right to down
+    pub fn apply(&self, p: Point) -> Point {
+        let mut p = p;
+        if self.mirrored {
+            p.x = -p.x;
+        }
+        for _ in 0..(self.turns % 4) {
+            p = Point{x: -p.y, y: p.x};
+        }
+        return p;
+    }
+
+    /// The orientation that undoes this one
+    pub fn inverse(&self) -> Orientation {
+        // Flips undo themselves, rotations go back the other way
+        if self.mirrored {
+            return *self;
+        }
+        return Orientation{turns: (4 - self.turns % 4) % 4, mirrored: false};
+    }
+}
@@ -0,0 +1,163 @@
+#![allow(dead_code)]
+use crate::tokenizer::picture::*;
+
+/// Which picture colors count as a definition color
+/// Drawn and screenshotted programs are hardly ever exactly the color they were meant to be
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ColorPredicate {
+    Exact(Color), // Only x itself
+    Tolerance(Color, u8), // Every channel is within y of x
+    Rgb(Color, Color), // Every channel is between x and y, inclusive
+    Hsv(HsvRange), // Hue, saturation and value are all in range
+    Set(String, Vec<Color>), // Any color in a named list, the name is one word of at most MAX_SET_NAME bytes
+    NonBackground, // Anything but white and green
+}
+
+// Longest a set name can be, saves write its length in a byte
+pub const MAX_SET_NAME: usize = 255;
+
+/// Inclusive hue, saturation and value ranges
+/// Hue is in degrees from 0 to 359 and wraps around, so 330 to 30 is reds
+/// Saturation and value are percents from 0 to 100
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HsvRange {
+    pub hue: (u16, u16),
+    pub saturation: (u8, u8),
+    pub value: (u8, u8),
+}
+
+/// Turns a color into hue in degrees, and saturation and value in percent
+pub fn to_hsv(c: Color) -> (u16, u8, u8) {
+    let (r, g, b) = (c.r as f32 / 255.0, c.g as f32 / 255.0, c.b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta == 0.0 {0.0}
+        else if max == r {60.0 * ((g - b) / delta).rem_euclid(6.0)}
+        else if max == g {60.0 * ((b - r) / delta + 2.0)}
+        else {60.0 * ((r - g) / delta + 4.0)};
+    let saturation = if max == 0.0 {0.0} else {delta / max};
+
+    return ((hue.round() as u16) % 360, (saturation * 100.0).round() as u8, (max * 100.0).round() as u8);
+}
+
+impl HsvRange {
+    pub fn contains(&self, c: Color) -> bool {
+        let (h, s, v) = to_hsv(c);
+        let (lo, hi) = self.hue;
+        let hue = if lo <= hi {lo <= h && h <= hi} else {h >= lo || h <= hi};
+        return hue
+            && self.saturation.0 <= s && s <= self.saturation.1
+            && self.value.0 <= v && v <= self.value.1;
+    }
+}
+
+impl ColorPredicate {
+    /// Whether a picture color counts
+    pub fn matches(&self, c: Color) -> bool {
+        match self {
+            ColorPredicate::Exact(x) => c == *x,
+            ColorPredicate::Tolerance(x, n) => c.r.abs_diff(x.r) <= *n && c.g.abs_diff(x.g) <= *n && c.b.abs_diff(x.b) <= *n,
+            ColorPredicate::Rgb(lo, hi) => {
+                lo.r <= c.r && c.r <= hi.r
+                    && lo.g <= c.g && c.g <= hi.g
+                    && lo.b <= c.b && c.b <= hi.b
+            }
+            ColorPredicate::Hsv(range) => range.contains(c),
+            ColorPredicate::Set(_, colors) => colors.contains(&c),
+            ColorPredicate::NonBackground => c != WHITE && c != GREEN,
+        }
+    }
+
+    /// Short description for labels, like `within 8 of #ff0000`
+    pub fn describe(&self) -> String {
+        match self {
+            ColorPredicate::Exact(x) => x.hex(),
+            ColorPredicate::Tolerance(x, n) => format!("within {} of {}", n, x.hex()),
+            ColorPredicate::Rgb(lo, hi) => format!("{} to {}", lo.hex(), hi.hex()),
+            ColorPredicate::Hsv(range) => format!(
+                "hue {}-{} sat {}-{}% val {}-{}%",
+                range.hue.0, range.hue.1, range.saturation.0, range.saturation.1, range.value.0, range.value.1
+            ),
+            ColorPredicate::Set(name, _) => name.clone(),
+            ColorPredicate::NonBackground => String::from("any color"),
+        }
+    }
+
+    /// Whether the predicate can be saved and loaded back the same, only set names can get in the way
+    /// Saves split words on whitespace, so a name has to be one word
+    pub fn well_named(&self) -> bool {
+        match self {
+            ColorPredicate::Set(name, _) => !name.is_empty() && name.len() <= MAX_SET_NAME && !name.contains(char::is_whitespace),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::ascii::Legend;
+    use crate::tokenizer::error::Error;
+    use crate::tokenizer::fsm::Fsm;
+    use crate::tokenizer::point::Point;
+    use crate::tokenizer::validate::Problem;
+    use super::*;
+
+    #[test]
+    /// Checks every kind of color predicate, and that they survive saving
+    fn consume_takes_predicates() {
+        let near_red = Color::from(240, 12, 8);
+        let pink = Color::from(255, 120, 140);
+        let preds = [
+            (ColorPredicate::Exact(RED), [true, false, false]),
+            (ColorPredicate::Tolerance(RED, 16), [true, true, false]),
+            (ColorPredicate::Rgb(Color::from(200, 0, 0), Color::from(255, 40, 40)), [true, true, false]),
+            (ColorPredicate::Hsv(HsvRange{hue: (340, 20), saturation: (80, 100), value: (50, 100)}), [true, true, false]),
+            (ColorPredicate::Set(String::from("reds"), vec![RED, pink]), [true, false, true]),
+            (ColorPredicate::NonBackground, [true, true, true]),
+        ];
+
+        let mut legend = Legend::new();
+        legend.add_function('F', BLUE).add_input('r', RED);
+        for (pred, expected) in preds {
+            let fsm = Fsm::from_ascii("rF", &legend).unwrap().accept(RED, pred.clone()).build().unwrap();
+            let text = Fsm::from_text(&fsm.to_text()).unwrap();
+            let bytes = Fsm::from_bytes(&fsm.to_bytes()).unwrap();
+            assert_eq!(text.to_text(), fsm.to_text());
+            for (c, expected) in [RED, near_red, pink].into_iter().zip(expected) {
+                let mut p = Picture::from_ascii("rF", &legend).unwrap();
+                p.set(0, 0, c);
+                assert_eq!(pred.matches(c), expected, "{:?} {:?}", pred, c);
+                for f in [&fsm, &text, &bytes] {
+                    let found = f.identify(&p);
+                    assert_eq!(found.is_some(), expected, "{:?} {:?}", pred, c);
+                    // Whatever color was there it counts as the definition color
+                    if let Some(found) = found {
+                        assert_eq!(found.inputs(), [Point::from(0, 0)]);
+                    }
+                }
+            }
+        }
+
+        // Background is never consumed even if a predicate would take it
+        let fsm = Fsm::from_ascii("rF", &legend).unwrap().accept(RED, ColorPredicate::Rgb(BLACK, WHITE)).build().unwrap();
+        assert!(fsm.identify(&Picture::from_ascii(".F", &legend).unwrap()).is_none());
+        assert!(matches!(Fsm::from_text(&fsm.to_text().replace("rgb", "rbg")), Err(Error::Parse{..})));
+
+        // Set names come back the same from both formats as long as they're one word that fits
+        for name in [String::from("#ff0000"), String::from("rötlich"), "é".repeat(127) + "x"] {
+            let fsm = Fsm::from_ascii("rF", &legend).unwrap().accept(RED, ColorPredicate::Set(name.clone(), vec![pink])).build().unwrap();
+            for loaded in [Fsm::from_text(&fsm.to_text()).unwrap(), Fsm::from_bytes(&fsm.to_bytes()).unwrap()] {
+                assert_eq!(loaded, fsm, "{}", name);
+            }
+        }
+        for name in [String::from("my reds"), String::new(), "é".repeat(128)] {
+            let built = Fsm::from_ascii("rF", &legend).unwrap().accept(RED, ColorPredicate::Set(name.clone(), vec![pink])).build();
+            match built {
+                Err(Error::Invalid{problems}) => assert!(matches!(&problems[..], [Problem::BadSetName{..}]), "{:?}", problems),
+                _ => panic!("{:?} built", name),
+            }
+        }
+    }
+}
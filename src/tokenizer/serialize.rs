@@ -0,0 +1,619 @@
+#![allow(dead_code)]
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use crate::tokenizer::point::*;
+use crate::tokenizer::picture::*;
+use crate::tokenizer::fsm::{Fsm, State, Transition, ColorType};
+use crate::tokenizer::error::Error;
+use crate::tokenizer::predicate::{ColorPredicate, HsvRange, MAX_SET_NAME};
+
+// Saving and loading compiled fsms so definitions don't have to be rebuilt from pictures every run
+//
+// Text format, one thing per line, blank lines and lines starting with // are skipped:
+//
+//     fsm 1                          <- format version, always first
+//     distinct_variables             <- only there if different variables have to bind to different colors
+//     exact_component                <- only there if a match has to take its whole connected component
+//     color #0000ff function         <- a definition color and what it's for (input, output, function, variable)
+//     orientation 1 mirrored         <- quarter turns clockwise, then plain or mirrored
+//     alternative 1                  <- first state of an alternative, 0 if there's only one
+//     state 0                        <- states are numbered in order starting at 0
+//         -> 1 consume #0000ff       <- transition to state 1, consuming a definition color
+//         -> 1 consume #0000ff any   <- same but some other picture colors count as it too, see below
+//         -> 2 move 0 1 -1           <- move relative to state 0 by (1, -1)
+//         -> 3 capture 8             <- start capture group 8
+//         -> 4 end 8                 <- end capture group 8
+//         -> 5 round 8               <- capture group 8 went round once more
+//         -> 6 epsilon               <- free transition
+//         -> 7 not 0 -1 any          <- passes if the pixel (0, -1) from the head isn't taken by a predicate
+//
+// Colors are written sorted so the same fsm always saves the same way, orientations and alternatives
+// stay in the order they get tried in.
+//
+// Which picture colors a consume takes goes after its color, and is left off if it's only the color itself.
+// Predicates are written like this, in consumes and in nots:
+//
+//     exact #ff0000                  <- only that color
+//     tolerance #ff0000 8            <- every channel within 8
+//     rgb #c00000 #ff4040            <- every channel in between, inclusive
+//     hsv 330 30 50 100 40 100       <- hue, saturation and value ranges, hue wraps around
+//     set reds #ff0000 #e00000       <- a named list of colors, the name is one word
+//     any                            <- anything but white and green
+//
+// Binary format, all numbers little endian:
+//
+//     "DVFSM" then the version as a u32
+//     u8 flags, 1 means different variables have to bind to different colors, 2 means exact component
+//     u32 color count, then per color: r, g, b, type (u8 each, 0 input 1 output 2 function 3 variable)
+//     u32 orientation count, then per orientation: turns, mirrored (u8 each)
+//     u32 alternative count, then per alternative: u32 first state
+//     u32 state count, then per state: u32 transition count, then per transition:
+//         u32 destination, u8 kind, then depending on kind
+//         0 move: u32 relative state, i32 x, i32 y
+//         1 consume: r, g, b, then u8 predicate kind, then depending on kind
+//             0 exact: r, g, b
+//             1 tolerance: r, g, b, u8 tolerance
+//             2 rgb: r, g, b low then r, g, b high
+//             3 hsv: u16 hue low, u16 hue high, u8 saturation low, high, value low, high
+//             4 set: u8 name length, name, u32 color count, r, g, b per color
+//             5 any: nothing
+//         2 capture: u8 group
+//         3 end capture: u8 group
+//         4 epsilon: nothing
+//         5 not: i32 x, i32 y, then a predicate written the same as in consume
+//         6 round: u8 group
+
+pub const VERSION: u32 = 1;
+pub const MAGIC: &[u8; 5] = b"DVFSM";
+
+fn type_name(t: ColorType) -> &'static str {
+    match t {
+        ColorType::Input => "input",
+        ColorType::Output => "output",
+        ColorType::Function => "function",
+        ColorType::Variable => "variable",
+    }
+}
+
+fn type_code(t: ColorType) -> u8 {
+    match t {
+        ColorType::Input => 0,
+        ColorType::Output => 1,
+        ColorType::Function => 2,
+        ColorType::Variable => 3,
+    }
+}
+
+/// Colors sorted so saving is always the same
+fn sorted_colors(f: &Fsm) -> Vec<(Color, ColorType)> {
+    let mut colors: Vec<(Color, ColorType)> = f.colors.iter().map(|(c, t)| (*c, *t)).collect();
+    colors.sort_by_key(|(c, _)| (c.r, c.g, c.b));
+    return colors;
+}
+
+/// Checks the fsm makes sense, gives back the first thing wrong with it
+fn check(f: &Fsm) -> Result<(), String> {
+    return match f.problems().first() {
+        Some(problem) => Err(problem.to_string()),
+        None => Ok(()),
+    };
+}
+
+/// Pulls numbers out of a byte slice, remembering where it is for error messages
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    path: Option<PathBuf>,
+}
+
+impl Reader<'_> {
+    fn error(&self, reason: &str) -> Error {
+        return Error::Binary{path: self.path.clone(), offset: self.offset, reason: String::from(reason)};
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], Error> {
+        if self.offset + n > self.bytes.len() {
+            return Err(self.error("file ends early"));
+        }
+        let taken = &self.bytes[self.offset..self.offset + n];
+        self.offset += n;
+        return Ok(taken);
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        return Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()));
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        return Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()));
+    }
+
+    fn color(&mut self) -> Result<Color, Error> {
+        return Ok(Color::from(self.u8()?, self.u8()?, self.u8()?));
+    }
+
+    fn predicate(&mut self) -> Result<ColorPredicate, Error> {
+        let predicate = match self.u8()? {
+            0 => ColorPredicate::Exact(self.color()?),
+            1 => ColorPredicate::Tolerance(self.color()?, self.u8()?),
+            2 => ColorPredicate::Rgb(self.color()?, self.color()?),
+            3 => {
+                let range = HsvRange{hue: (self.u16()?, self.u16()?), saturation: (self.u8()?, self.u8()?), value: (self.u8()?, self.u8()?)};
+                if range.hue.0 >= 360 || range.hue.1 >= 360 || range.saturation.1 > 100 || range.value.1 > 100 {
+                    return Err(self.error("hsv range out of bounds"));
+                }
+                ColorPredicate::Hsv(range)
+            }
+            4 => {
+                let len = self.u8()? as usize;
+                let name = String::from_utf8(self.take(len)?.to_vec()).map_err(|_| self.error("color set name isn't utf8"))?;
+                let mut colors = vec![];
+                for _ in 0..self.u32()? {
+                    colors.push(self.color()?);
+                }
+                ColorPredicate::Set(name, colors)
+            }
+            5 => ColorPredicate::NonBackground,
+            _ => return Err(self.error("unknown color predicate")),
+        };
+        return Ok(predicate);
+    }
+}
+
+/// Writes out which picture colors a consume takes, for the text format
+fn predicate_text(predicate: &ColorPredicate) -> String {
+    match predicate {
+        ColorPredicate::Exact(c) => format!("exact {}", c.hex()),
+        ColorPredicate::Tolerance(c, n) => format!("tolerance {} {}", c.hex(), n),
+        ColorPredicate::Rgb(lo, hi) => format!("rgb {} {}", lo.hex(), hi.hex()),
+        ColorPredicate::Hsv(range) => format!(
+            "hsv {} {} {} {} {} {}",
+            range.hue.0, range.hue.1, range.saturation.0, range.saturation.1, range.value.0, range.value.1
+        ),
+        ColorPredicate::Set(name, colors) => {
+            let mut out = format!("set {}", name);
+            for c in colors {
+                out += &format!(" {}", c.hex());
+            }
+            out
+        }
+        ColorPredicate::NonBackground => String::from("any"),
+    }
+}
+
+/// Writes out which picture colors a consume takes, for the binary format
+fn predicate_bytes(predicate: &ColorPredicate, out: &mut Vec<u8>) {
+    match predicate {
+        ColorPredicate::Exact(c) => out.extend([0, c.r, c.g, c.b]),
+        ColorPredicate::Tolerance(c, n) => out.extend([1, c.r, c.g, c.b, *n]),
+        ColorPredicate::Rgb(lo, hi) => out.extend([2, lo.r, lo.g, lo.b, hi.r, hi.g, hi.b]),
+        ColorPredicate::Hsv(range) => {
+            out.push(3);
+            out.extend(range.hue.0.to_le_bytes());
+            out.extend(range.hue.1.to_le_bytes());
+            out.extend([range.saturation.0, range.saturation.1, range.value.0, range.value.1]);
+        }
+        ColorPredicate::Set(name, colors) => {
+            // Names longer than a u8 can count don't validate, if one gets here anyway it's cut short
+            // on a character boundary so it still loads
+            let mut end = name.len().min(MAX_SET_NAME);
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            let name = &name.as_bytes()[..end];
+            out.extend([4, name.len() as u8]);
+            out.extend(name);
+            out.extend((colors.len() as u32).to_le_bytes());
+            for c in colors {
+                out.extend([c.r, c.g, c.b]);
+            }
+        }
+        ColorPredicate::NonBackground => out.push(5),
+    }
+}
+
+impl Fsm {
+    /// Writes the fsm out in the text format
+    pub fn to_text(&self) -> String {
+        let mut out = format!("fsm {}\n", VERSION);
+        if self.distinct_variables {
+            out += "distinct_variables\n";
+        }
+        if self.exact_component {
+            out += "exact_component\n";
+        }
+        for (c, t) in sorted_colors(self) {
+            out += &format!("color {} {}\n", c.hex(), type_name(t));
+        }
+        for o in self.orientations.iter().copied() {
+            out += &format!("orientation {} {}\n", o.turns, if o.mirrored {"mirrored"} else {"plain"});
+        }
+        for a in self.alternatives.iter() {
+            out += &format!("alternative {}\n", a);
+        }
+        for (i, state) in self.states.iter().enumerate() {
+            out += &format!("state {}\n", i);
+            for (destination, transition) in state.t.iter() {
+                let what = match transition {
+                    Transition::MoveRelative(rel_state, p) => format!("move {} {} {}", rel_state, p.x, p.y),
+                    Transition::Consume(c, predicate) if *predicate == ColorPredicate::Exact(*c) => format!("consume {}", c.hex()),
+                    Transition::Consume(c, predicate) => format!("consume {} {}", c.hex(), predicate_text(predicate)),
+                    Transition::Capture(g) => format!("capture {}", g),
+                    Transition::EndCapture(g) => format!("end {}", g),
+                    Transition::Round(g) => format!("round {}", g),
+                    Transition::Epsilon => String::from("epsilon"),
+                    Transition::NotAt(p, predicate) => format!("not {} {} {}", p.x, p.y, predicate_text(predicate)),
+                };
+                out += &format!("    -> {} {}\n", destination, what);
+            }
+        }
+        return out;
+    }
+
+    /// Reads an fsm back out of the text format
+    pub fn from_text(text: &str) -> Result<Fsm, Error> {
+        return Fsm::parse_text(text, None);
+    }
+
+    fn parse_text(text: &str, path: Option<&Path>) -> Result<Fsm, Error> {
+        let error = |line: usize, reason: String| Error::Parse{path: path.map(|p| p.to_path_buf()), line, reason};
+
+        let mut fsm = Fsm{states: vec![], colors: HashMap::new(), orientations: vec![], distinct_variables: false, exact_component: false, alternatives: vec![]};
+        let mut seen_version = false;
+        for (n, line) in text.lines().enumerate() {
+            let n = n + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {continue}
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = |i: usize| -> Result<i64, Error> {
+                let word = words.get(i).ok_or_else(|| error(n, String::from("line ends early")))?;
+                return word.parse::<i64>().map_err(|_| error(n, format!("expected a number, found {}", word)));
+            };
+            let index = |i: usize| -> Result<usize, Error> {
+                return usize::try_from(number(i)?).map_err(|_| error(n, String::from("expected a state number")));
+            };
+            let group = |i: usize| -> Result<u8, Error> {
+                return u8::try_from(number(i)?).map_err(|_| error(n, String::from("capture groups go from 0 to 255")));
+            };
+            let color = |i: usize| -> Result<Color, Error> {
+                let word = words.get(i).ok_or_else(|| error(n, String::from("line ends early")))?;
+                return Color::from_hex(word).ok_or_else(|| error(n, format!("expected a color like #00ff00, found {}", word)));
+            };
+            // Predicate whose kind is word i
+            let predicate = |i: usize| -> Result<ColorPredicate, Error> {
+                let predicate = match words.get(i).copied() {
+                    None => return Err(error(n, String::from("line ends early"))),
+                    Some("exact") => ColorPredicate::Exact(color(i + 1)?),
+                    Some("tolerance") => {
+                        let tolerance = u8::try_from(number(i + 2)?).map_err(|_| error(n, String::from("tolerance goes from 0 to 255")))?;
+                        ColorPredicate::Tolerance(color(i + 1)?, tolerance)
+                    }
+                    Some("rgb") => ColorPredicate::Rgb(color(i + 1)?, color(i + 2)?),
+                    Some("hsv") => {
+                        let hue = |i: usize| u16::try_from(number(i)?).ok().filter(|h| *h < 360).ok_or_else(|| error(n, String::from("hue goes from 0 to 359")));
+                        let percent = |i: usize| u8::try_from(number(i)?).ok().filter(|p| *p <= 100).ok_or_else(|| error(n, String::from("saturation and value go from 0 to 100")));
+                        ColorPredicate::Hsv(HsvRange{hue: (hue(i + 1)?, hue(i + 2)?), saturation: (percent(i + 3)?, percent(i + 4)?), value: (percent(i + 5)?, percent(i + 6)?)})
+                    }
+                    Some("set") => {
+                        let name = words.get(i + 1).ok_or_else(|| error(n, String::from("line ends early")))?;
+                        let colors = (i + 2..words.len()).map(color).collect::<Result<Vec<Color>, Error>>()?;
+                        ColorPredicate::Set(name.to_string(), colors)
+                    }
+                    Some("any") => ColorPredicate::NonBackground,
+                    Some(word) => return Err(error(n, format!("unknown color predicate {}", word))),
+                };
+                return Ok(predicate);
+            };
+
+            if !seen_version {
+                if words[0] != "fsm" {
+                    return Err(error(n, String::from("expected fsm and a version first")));
+                }
+                let version = number(1)?;
+                if version != VERSION as i64 {
+                    return Err(Error::Version{path: path.map(|p| p.to_path_buf()), version: version as u32});
+                }
+                seen_version = true;
+                continue;
+            }
+
+            match words[0] {
+                "distinct_variables" => fsm.distinct_variables = true,
+                "exact_component" => fsm.exact_component = true,
+                "color" => {
+                    let t = match words.get(2).copied() {
+                        Some("input") => ColorType::Input,
+                        Some("output") => ColorType::Output,
+                        Some("function") => ColorType::Function,
+                        Some("variable") => ColorType::Variable,
+                        _ => return Err(error(n, String::from("color type has to be input, output, function or variable"))),
+                    };
+                    fsm.colors.insert(color(1)?, t);
+                }
+                "orientation" => {
+                    let turns = u8::try_from(number(1)?).ok().filter(|t| *t < 4).ok_or_else(|| error(n, String::from("turns go from 0 to 3")))?;
+                    let mirrored = match words.get(2).copied() {
+                        Some("plain") => false,
+                        Some("mirrored") => true,
+                        _ => return Err(error(n, String::from("orientation has to be plain or mirrored"))),
+                    };
+                    fsm.orientations.push(Orientation{turns, mirrored});
+                }
+                "alternative" => fsm.alternatives.push(index(1)?),
+                "state" => {
+                    if index(1)? != fsm.states.len() {
+                        return Err(error(n, format!("expected state {}", fsm.states.len())));
+                    }
+                    fsm.states.push(State::new());
+                }
+                "->" => {
+                    let destination = index(1)?;
+                    let transition = match words.get(2).copied() {
+                        Some("move") => {
+                            let x = i32::try_from(number(4)?).map_err(|_| error(n, String::from("move is too far")))?;
+                            let y = i32::try_from(number(5)?).map_err(|_| error(n, String::from("move is too far")))?;
+                            Transition::MoveRelative(index(3)?, Point::from(x, y))
+                        }
+                        Some("consume") => {
+                            let c = color(3)?;
+                            let predicate = if words.len() == 4 {ColorPredicate::Exact(c)} else {predicate(4)?};
+                            Transition::Consume(c, predicate)
+                        }
+                        Some("not") => {
+                            let x = i32::try_from(number(3)?).map_err(|_| error(n, String::from("offset is too far")))?;
+                            let y = i32::try_from(number(4)?).map_err(|_| error(n, String::from("offset is too far")))?;
+                            Transition::NotAt(Point::from(x, y), predicate(5)?)
+                        }
+                        Some("capture") => Transition::Capture(group(3)?),
+                        Some("end") => Transition::EndCapture(group(3)?),
+                        Some("round") => Transition::Round(group(3)?),
+                        Some("epsilon") => Transition::Epsilon,
+                        _ => return Err(error(n, String::from("unknown transition"))),
+                    };
+                    let Some(state) = fsm.states.last_mut() else {
+                        return Err(error(n, String::from("transition before any state")));
+                    };
+                    state.t.push((destination, transition));
+                }
+                word => return Err(error(n, format!("unknown line starting with {}", word))),
+            }
+        }
+
+        if !seen_version {
+            return Err(error(0, String::from("empty file")));
+        }
+        if fsm.alternatives.is_empty() {
+            fsm.alternatives.push(0);
+        }
+        check(&fsm).map_err(|reason| error(text.lines().count(), reason))?;
+        if fsm.orientations.is_empty() {
+            fsm.orientations.push(Orientation::IDENTITY);
+        }
+        return Ok(fsm);
+    }
+
+    /// Writes the fsm out in the binary format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_le_bytes());
+        out.push(self.distinct_variables as u8 | (self.exact_component as u8) << 1);
+
+        let colors = sorted_colors(self);
+        out.extend((colors.len() as u32).to_le_bytes());
+        for (c, t) in colors {
+            out.extend([c.r, c.g, c.b, type_code(t)]);
+        }
+
+        out.extend((self.orientations.len() as u32).to_le_bytes());
+        for o in self.orientations.iter() {
+            out.extend([o.turns, o.mirrored as u8]);
+        }
+
+        out.extend((self.alternatives.len() as u32).to_le_bytes());
+        for a in self.alternatives.iter() {
+            out.extend((*a as u32).to_le_bytes());
+        }
+
+        out.extend((self.states.len() as u32).to_le_bytes());
+        for state in self.states.iter() {
+            out.extend((state.t.len() as u32).to_le_bytes());
+            for (destination, transition) in state.t.iter() {
+                out.extend((*destination as u32).to_le_bytes());
+                match transition {
+                    Transition::MoveRelative(rel_state, p) => {
+                        out.push(0);
+                        out.extend((*rel_state as u32).to_le_bytes());
+                        out.extend(p.x.to_le_bytes());
+                        out.extend(p.y.to_le_bytes());
+                    }
+                    Transition::Consume(c, predicate) => {
+                        out.extend([1, c.r, c.g, c.b]);
+                        predicate_bytes(predicate, &mut out);
+                    }
+                    Transition::Capture(g) => out.extend([2, *g]),
+                    Transition::EndCapture(g) => out.extend([3, *g]),
+                    Transition::Round(g) => out.extend([6, *g]),
+                    Transition::Epsilon => out.push(4),
+                    Transition::NotAt(p, predicate) => {
+                        out.push(5);
+                        out.extend(p.x.to_le_bytes());
+                        out.extend(p.y.to_le_bytes());
+                        predicate_bytes(predicate, &mut out);
+                    }
+                }
+            }
+        }
+        return out;
+    }
+
+    /// Reads an fsm back out of the binary format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Fsm, Error> {
+        return Fsm::parse_bytes(bytes, None);
+    }
+
+    fn parse_bytes(bytes: &[u8], path: Option<&Path>) -> Result<Fsm, Error> {
+        let mut r = Reader{bytes, offset: 0, path: path.map(|p| p.to_path_buf())};
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(Error::Binary{path: r.path.clone(), offset: 0, reason: String::from("not a saved fsm")});
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(Error::Version{path: r.path.clone(), version});
+        }
+
+        let flags = r.u8()?;
+        if flags > 3 {
+            return Err(r.error("unknown flags"));
+        }
+        let mut fsm = Fsm{
+            states: vec![],
+            colors: HashMap::new(),
+            orientations: vec![],
+            distinct_variables: flags & 1 != 0,
+            exact_component: flags & 2 != 0,
+            alternatives: vec![],
+        };
+        for _ in 0..r.u32()? {
+            let c = r.color()?;
+            let t = match r.u8()? {
+                0 => ColorType::Input,
+                1 => ColorType::Output,
+                2 => ColorType::Function,
+                3 => ColorType::Variable,
+                _ => return Err(r.error("unknown color type")),
+            };
+            fsm.colors.insert(c, t);
+        }
+        for _ in 0..r.u32()? {
+            let turns = r.u8()?;
+            let mirrored = r.u8()?;
+            if turns > 3 || mirrored > 1 {
+                return Err(r.error("unknown orientation"));
+            }
+            fsm.orientations.push(Orientation{turns, mirrored: mirrored == 1});
+        }
+        for _ in 0..r.u32()? {
+            fsm.alternatives.push(r.u32()? as usize);
+        }
+        if fsm.alternatives.is_empty() {
+            fsm.alternatives.push(0);
+        }
+        for _ in 0..r.u32()? {
+            let mut state = State::new();
+            for _ in 0..r.u32()? {
+                let destination = r.u32()? as usize;
+                let transition = match r.u8()? {
+                    0 => {
+                        let rel_state = r.u32()? as usize;
+                        Transition::MoveRelative(rel_state, Point::from(r.i32()?, r.i32()?))
+                    }
+                    1 => Transition::Consume(r.color()?, r.predicate()?),
+                    2 => Transition::Capture(r.u8()?),
+                    3 => Transition::EndCapture(r.u8()?),
+                    4 => Transition::Epsilon,
+                    5 => Transition::NotAt(Point::from(r.i32()?, r.i32()?), r.predicate()?),
+                    6 => Transition::Round(r.u8()?),
+                    _ => return Err(r.error("unknown transition")),
+                };
+                state.t.push((destination, transition));
+            }
+            fsm.states.push(state);
+        }
+
+        if r.offset != bytes.len() {
+            return Err(r.error("extra bytes after the last state"));
+        }
+        check(&fsm).map_err(|reason| r.error(&reason))?;
+        if fsm.orientations.is_empty() {
+            fsm.orientations.push(Orientation::IDENTITY);
+        }
+        return Ok(fsm);
+    }
+
+    /// Saves the fsm in the text format
+    pub fn save_text<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        return std::fs::write(path, self.to_text()).map_err(|source| Error::Io{path: path.to_path_buf(), source});
+    }
+
+    /// Saves the fsm in the binary format
+    pub fn save_bytes<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        return std::fs::write(path, self.to_bytes()).map_err(|source| Error::Io{path: path.to_path_buf(), source});
+    }
+
+    /// Loads a saved fsm, works out by itself whether it's text or binary
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Fsm, Error> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(|source| Error::Io{path: path.to_path_buf(), source})?;
+        if bytes.starts_with(MAGIC) {
+            return Fsm::parse_bytes(&bytes, Some(path));
+        }
+        let text = String::from_utf8(bytes).map_err(|_| Error::Parse{path: Some(path.to_path_buf()), line: 0, reason: String::from("not text or a binary fsm")})?;
+        return Fsm::parse_text(&text, Some(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::fsm::tests::definitions;
+    use super::*;
+
+    #[test]
+    /// Checks that saving and loading an fsm gives the same fsm back, both ways of saving it
+    fn saves_and_loads() {
+        let mut pictures = definitions();
+        pictures.push(Picture::open_pic("./builtin/FStart.png").unwrap());
+
+        for p in pictures.iter() {
+            let fsm = Fsm::builder(p).unwrap()
+                .add_input(Color::from(0, 148, 255))
+                .add_output(Color::from(178, 0, 255))
+                .any_orientation()
+                .build().unwrap();
+
+            let text = fsm.to_text();
+            let from_text = Fsm::from_text(&text).unwrap();
+            let from_bytes = Fsm::from_bytes(&fsm.to_bytes()).unwrap();
+            for loaded in [from_text, from_bytes] {
+                assert_eq!(format!("{:?}", loaded.states), format!("{:?}", fsm.states));
+                assert_eq!(loaded.colors, fsm.colors);
+                assert_eq!(loaded.orientations, fsm.orientations);
+                assert_eq!(loaded.to_text(), text);
+                assert_eq!(loaded.identify(p), fsm.identify(p));
+            }
+        }
+
+        let dir = std::env::temp_dir();
+        let fsm = Fsm::builder(&pictures[0]).unwrap().build().unwrap();
+        fsm.save_text(dir.join("davinc_saves_and_loads.fsm")).unwrap();
+        fsm.save_bytes(dir.join("davinc_saves_and_loads.fsmb")).unwrap();
+        assert_eq!(Fsm::load(dir.join("davinc_saves_and_loads.fsm")).unwrap().to_text(), fsm.to_text());
+        assert_eq!(Fsm::load(dir.join("davinc_saves_and_loads.fsmb")).unwrap().to_text(), fsm.to_text());
+    }
+
+    #[test]
+    /// Checks that broken saved fsms are errors that say where they're broken
+    fn bad_saves_error() {
+        let good = "fsm 1\ncolor #0000ff function\nstate 0\n    -> 1 consume #0000ff\nstate 1\n";
+        assert!(Fsm::from_text(good).is_ok());
+
+        assert!(matches!(Fsm::from_text("fsm 2\n"), Err(Error::Version{version: 2, ..})));
+        assert!(matches!(Fsm::from_text(&good.replace("-> 1", "-> 7")), Err(Error::Parse{..})));
+        assert!(matches!(Fsm::from_text(&good.replace("function", "function\nstate 3")), Err(Error::Parse{line: 3, ..})));
+        assert!(matches!(Fsm::from_text(&good.replace("#0000ff function", "#0000ff input")), Err(Error::Parse{..})));
+        assert!(matches!(Fsm::from_text(&good.replace("consume #0000ff", "consume blue")), Err(Error::Parse{line: 4, ..})));
+
+        let bytes = Fsm::from_text(good).unwrap().to_bytes();
+        assert!(matches!(Fsm::from_bytes(&bytes[..bytes.len() - 1]), Err(Error::Binary{..})));
+        assert!(matches!(Fsm::from_bytes(&bytes[1..]), Err(Error::Binary{offset: 0, ..})));
+    }
+}
@@ -1,7 +1,8 @@
-use std::fs;
+#![allow(dead_code)]
 use crate::Color;
 use crate::Picture;
 use crate::Fsm;
+use crate::Error;
 
 /// List of built in operations
 /// For all Binary Operations structure is:
@@ -21,11 +22,15 @@ struct Node {
     next: Box<Node>,
 }
 
-pub fn tokenize(p: &Picture) {
-    let mut start = Fsm::builder(&Picture::open_pic("./builtin/FStart.png"))
-        .add_input(Color::from(0, 148, 255))
-        .add_output(Color::from(178, 0, 255))
-        .build();
+pub fn tokenize(p: &Picture) -> Result<(), Error> {
+    let start = Fsm::builder(&Picture::open_pic("./builtin/FStart.png")?)?
+        // Operands can be any color the programmer likes, but the same color all the way through
+        .add_variable(Color::from(0, 148, 255))
+        .add_variable(Color::from(178, 0, 255))
+        .build()?;
 
-    print!("{:?}", start.identify(p));
+    for found in start.find_iter(p) {
+        println!("{:?}", found);
+    }
+    return Ok(());
 }
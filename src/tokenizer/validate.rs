@@ -0,0 +1,428 @@
+#![allow(dead_code)]
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use crate::tokenizer::fsm::{Fsm, Transition, ColorType};
+use crate::tokenizer::picture::Color;
+use crate::tokenizer::error::Error;
+use crate::tokenizer::predicate::{ColorPredicate, MAX_SET_NAME};
+
+// Capture groups open inside each other deeper than this are taken to be opening forever
+const MAX_DEPTH: usize = 256;
+
+/// Something wrong with an fsm, found by Fsm::validate
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Problem {
+    NoStates, // Nothing to match with
+    NoFunctionColor, // No color is the function color, so there's nothing to anchor on
+    BadAlternatives, // Alternatives are out of order or start at states that don't exist
+    MissingState{state: usize, destination: usize}, // State x has a transition to a state that doesn't exist
+    MissingRelative{state: usize, relative: usize}, // State x moves relative to a state that doesn't exist
+    RelativeNotBefore{state: usize, relative: usize}, // State x moves relative to a state that isn't always gone through before it
+    UnknownColor{state: usize, color: Color}, // State x consumes a color that isn't a definition color
+    BadSetName{state: usize, name: String}, // State x takes a color set whose name isn't one word short enough to save
+    EndsUnopened{state: usize, group: u8}, // State x can end capture group y when it isn't open
+    EndsOutOfOrder{state: usize, group: u8}, // State x can end capture group y while a group opened inside it is still open
+    RoundUnopened{state: usize, group: u8}, // State x can count a round for capture group y when it isn't open
+    LeftOpen{state: usize, group: u8}, // Finish state x can be reached with capture group y still open
+    OpensForever{state: usize}, // State x is on a loop that keeps opening capture groups without ending them
+    FreeLoop{state: usize}, // State x is on a loop that can be gone round without consuming anything
+    DeadEnd{state: usize}, // State x can't reach a finish state
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::NoStates => write!(f, "no states"),
+            Problem::NoFunctionColor => write!(f, "no function color"),
+            Problem::BadAlternatives => write!(f, "alternatives are out of order or start at states that don't exist"),
+            Problem::MissingState{state, destination} => write!(f, "state {} goes to state {} which doesn't exist", state, destination),
+            Problem::MissingRelative{state, relative} => write!(f, "state {} moves relative to state {} which doesn't exist", state, relative),
+            Problem::RelativeNotBefore{state, relative} => write!(f, "state {} moves relative to state {} which isn't always gone through before it", state, relative),
+            Problem::UnknownColor{state, color} => write!(f, "state {} consumes {} which isn't a definition color", state, color.hex()),
+            Problem::BadSetName{state, name} => write!(f, "state {} takes color set {:?} whose name isn't one word of at most {} bytes", state, name, MAX_SET_NAME),
+            Problem::EndsUnopened{state, group} => write!(f, "state {} can end capture group {} when it isn't open", state, group),
+            Problem::EndsOutOfOrder{state, group} => write!(f, "state {} can end capture group {} while a group inside it is still open", state, group),
+            Problem::RoundUnopened{state, group} => write!(f, "state {} can count a round for capture group {} when it isn't open", state, group),
+            Problem::LeftOpen{state, group} => write!(f, "finish state {} can be reached with capture group {} still open", state, group),
+            Problem::OpensForever{state} => write!(f, "state {} keeps opening capture groups without ending them", state),
+            Problem::FreeLoop{state} => write!(f, "state {} is on a loop that can be gone round without consuming anything", state),
+            Problem::DeadEnd{state} => write!(f, "state {} can't reach a finish state", state),
+        }
+    }
+}
+
+impl Fsm {
+    /// Checks the fsm makes sense before anything is matched with it
+    /// Fsms from build and load are always checked, ones put together by hand should be too
+    pub fn validate(&self) -> Result<(), Error> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        return Err(Error::Invalid{problems});
+    }
+
+    /// Everything wrong with the fsm, in the order it was found
+    /// Transitions going to states that don't exist are looked for first, nothing else is checked if there are any
+    pub fn problems(&self) -> Vec<Problem> {
+        let states = &self.states;
+        if states.is_empty() {
+            return vec![Problem::NoStates];
+        }
+        let mut problems = vec![];
+        if !self.colors.values().any(|t| *t == ColorType::Function) {
+            problems.push(Problem::NoFunctionColor);
+        }
+        if self.alternatives.windows(2).any(|w| w[0] >= w[1]) || self.alternatives.iter().any(|a| *a >= states.len()) {
+            problems.push(Problem::BadAlternatives);
+        }
+        for (i, state) in states.iter().enumerate() {
+            for (destination, transition) in state.t.iter() {
+                if *destination >= states.len() {
+                    problems.push(Problem::MissingState{state: i, destination: *destination});
+                }
+                match transition {
+                    Transition::MoveRelative(relative, _) if *relative >= states.len() => {
+                        problems.push(Problem::MissingRelative{state: i, relative: *relative});
+                    }
+                    Transition::Consume(color, _) if !self.colors.contains_key(color) => {
+                        problems.push(Problem::UnknownColor{state: i, color: *color});
+                    }
+                    _ => {}
+                }
+                let predicate = match transition {
+                    Transition::Consume(_, predicate) | Transition::NotAt(_, predicate) => Some(predicate),
+                    _ => None,
+                };
+                if let Some(ColorPredicate::Set(name, _)) = predicate.filter(|p| !p.well_named()) {
+                    problems.push(Problem::BadSetName{state: i, name: name.clone()});
+                }
+            }
+        }
+        if problems.iter().any(|p| matches!(p, Problem::MissingState{..} | Problem::MissingRelative{..})) {
+            return problems;
+        }
+
+        self.check_relatives(&mut problems);
+        self.check_captures(&mut problems);
+        self.check_free_loops(&mut problems);
+        self.check_dead_ends(&mut problems);
+        return problems;
+    }
+
+    /// Moving relative to a state only makes sense if the head has been there on every way to the move
+    /// That's the state dominating the one with the move in it
+    fn check_relatives(&self, problems: &mut Vec<Problem>) {
+        // Reverse postorder from the start state, only reachable states get numbered
+        let n = self.states.len();
+        let mut order = vec![usize::MAX; n];
+        let mut postorder = vec![];
+        let mut visited = vec![false; n];
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((state, next)) = stack.pop() {
+            if let Some((destination, _)) = self.states[state].t.get(next) {
+                stack.push((state, next + 1));
+                if !visited[*destination] {
+                    visited[*destination] = true;
+                    stack.push((*destination, 0));
+                }
+            }
+            else {
+                postorder.push(state);
+            }
+        }
+        let rpo: Vec<usize> = postorder.into_iter().rev().collect();
+        for (i, state) in rpo.iter().enumerate() {
+            order[*state] = i;
+        }
+        let mut preds = vec![vec![]; n];
+        for (i, state) in self.states.iter().enumerate() {
+            for (destination, _) in state.t.iter() {
+                preds[*destination].push(i);
+            }
+        }
+
+        // Cooper, Harvey and Kennedy's iterative immediate dominators
+        let mut idom = vec![usize::MAX; n];
+        idom[0] = 0;
+        let intersect = |idom: &Vec<usize>, mut a: usize, mut b: usize| {
+            while a != b {
+                while order[a] > order[b] {a = idom[a]}
+                while order[b] > order[a] {b = idom[b]}
+            }
+            return a;
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for state in rpo.iter().skip(1) {
+                let mut new_idom = usize::MAX;
+                for pred in preds[*state].iter() {
+                    if idom[*pred] == usize::MAX {continue}
+                    new_idom = if new_idom == usize::MAX {*pred} else {intersect(&idom, *pred, new_idom)};
+                }
+                if idom[*state] != new_idom {
+                    idom[*state] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let dominates = |a: usize, mut b: usize| {
+            loop {
+                if a == b {return true}
+                if b == 0 {return false}
+                b = idom[b];
+            }
+        };
+        for state in rpo.iter() {
+            for (_, transition) in self.states[*state].t.iter() {
+                if let Transition::MoveRelative(relative, _) = transition {
+                    if !dominates(*relative, *state) {
+                        problems.push(Problem::RelativeNotBefore{state: *state, relative: *relative});
+                    }
+                }
+            }
+        }
+    }
+
+    /// Walks every way through the fsm keeping track of which capture groups are open
+    fn check_captures(&self, problems: &mut Vec<Problem>) {
+        let mut found = HashSet::new();
+        let mut seen: HashSet<(usize, Vec<u8>)> = HashSet::new();
+        let mut stack = vec![(0, vec![])];
+        while let Some((state, open)) = stack.pop() {
+            if !seen.insert((state, open.clone())) {continue}
+            if self.states[state].t.is_empty() {
+                if let Some(group) = open.last() {
+                    let problem = Problem::LeftOpen{state, group: *group};
+                    if found.insert(problem.clone()) {
+                        problems.push(problem);
+                    }
+                }
+                continue;
+            }
+            for (destination, transition) in self.states[state].t.iter() {
+                let mut open = open.clone();
+                let problem = match transition {
+                    Transition::Capture(_) if open.len() >= MAX_DEPTH => Some(Problem::OpensForever{state}),
+                    Transition::Capture(g) => {
+                        open.push(*g);
+                        None
+                    }
+                    Transition::EndCapture(g) if open.last() == Some(g) => {
+                        open.pop();
+                        None
+                    }
+                    Transition::EndCapture(g) if open.contains(g) => Some(Problem::EndsOutOfOrder{state, group: *g}),
+                    Transition::EndCapture(g) => Some(Problem::EndsUnopened{state, group: *g}),
+                    Transition::Round(g) if !open.contains(g) => Some(Problem::RoundUnopened{state, group: *g}),
+                    _ => None,
+                };
+                // Only said once however many ways lead to it, and nothing after it is looked at
+                if let Some(problem) = problem {
+                    if found.insert(problem.clone()) {
+                        problems.push(problem);
+                    }
+                    continue;
+                }
+                stack.push((*destination, open));
+            }
+        }
+    }
+
+    /// Going round a loop has to take a pixel, otherwise rounds can be counted forever on the same pixel
+    /// and matching never finishes
+    /// Matching never takes an epsilon straight back to the state it came from for free, so loops are looked
+    /// for among states paired with where they were got to from
+    fn check_free_loops(&self, problems: &mut Vec<Problem>) {
+        // Every state got to by a move or from nowhere, then every state got to for free from another one
+        let mut nodes: Vec<(usize, Option<usize>)> = (0..self.states.len()).map(|state| (state, None)).collect();
+        for (i, state) in self.states.iter().enumerate() {
+            for (destination, transition) in state.t.iter() {
+                if !matches!(transition, Transition::Consume(..) | Transition::MoveRelative(..)) {
+                    nodes.push((*destination, Some(i)));
+                }
+            }
+        }
+        nodes.sort();
+        nodes.dedup();
+        let id = |node: (usize, Option<usize>)| nodes.binary_search(&node).unwrap();
+        let edges: Vec<Vec<usize>> = nodes.iter().map(|(state, from)| {
+            self.states[*state].t.iter().filter_map(|(destination, transition)| match transition {
+                Transition::Consume(..) => None,
+                Transition::MoveRelative(..) => Some(id((*destination, None))),
+                Transition::Epsilon if *from == Some(*destination) => None,
+                _ => Some(id((*destination, Some(*state)))),
+            }).collect()
+        }).collect();
+
+        // Tarjan's strongly connected components, with a stack of its own instead of recursion
+        let n = nodes.len();
+        let mut index = vec![usize::MAX; n];
+        let mut low = vec![0; n];
+        let mut on_stack = vec![false; n];
+        let mut component = vec![];
+        let mut count = 0;
+        let mut found = HashSet::new();
+        for root in 0..n {
+            if index[root] != usize::MAX {continue}
+            let mut walk = vec![(root, 0)];
+            index[root] = count;
+            low[root] = count;
+            count += 1;
+            component.push(root);
+            on_stack[root] = true;
+            while let Some((node, next)) = walk.pop() {
+                if let Some(&destination) = edges[node].get(next) {
+                    walk.push((node, next + 1));
+                    if index[destination] == usize::MAX {
+                        index[destination] = count;
+                        low[destination] = count;
+                        count += 1;
+                        component.push(destination);
+                        on_stack[destination] = true;
+                        walk.push((destination, 0));
+                    }
+                    else if on_stack[destination] {
+                        low[node] = low[node].min(index[destination]);
+                    }
+                    continue;
+                }
+                if let Some((parent, _)) = walk.last() {
+                    low[*parent] = low[*parent].min(low[node]);
+                }
+                if low[node] != index[node] {continue}
+                let mut members = vec![];
+                while let Some(member) = component.pop() {
+                    on_stack[member] = false;
+                    members.push(member);
+                    if member == node {break}
+                }
+                if members.len() > 1 || edges[node].contains(&node) {
+                    let state = members.iter().map(|m| nodes[*m].0).min().unwrap();
+                    if found.insert(state) {
+                        problems.push(Problem::FreeLoop{state});
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every state has to be able to get to a finish state, otherwise threads going there are wasted
+    fn check_dead_ends(&self, problems: &mut Vec<Problem>) {
+        let mut preds: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (i, state) in self.states.iter().enumerate() {
+            for (destination, _) in state.t.iter() {
+                preds.entry(*destination).or_default().push(i);
+            }
+        }
+        let mut finishes: Vec<usize> = (0..self.states.len()).filter(|i| self.states[*i].t.is_empty()).collect();
+        let mut live: HashSet<usize> = finishes.iter().copied().collect();
+        while let Some(state) = finishes.pop() {
+            for pred in preds.get(&state).into_iter().flatten() {
+                if live.insert(*pred) {
+                    finishes.push(*pred);
+                }
+            }
+        }
+        for state in 0..self.states.len() {
+            if !live.contains(&state) {
+                problems.push(Problem::DeadEnd{state});
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::fsm::State;
+    use crate::tokenizer::picture::{Picture, BLUE, RED};
+    use crate::tokenizer::point::{Point, Orientation};
+    use super::*;
+
+    #[test]
+    /// Checks that validate finds everything wrong with hand built fsms, and that matching them anyway doesn't fall over
+    fn validate_finds_problems() {
+        let fsm = |states: Vec<Vec<(usize, Transition)>>| Fsm{
+            states: states.into_iter().map(|t| State{t}).collect(),
+            colors: HashMap::from([(BLUE, ColorType::Function)]),
+            orientations: vec![Orientation::IDENTITY],
+            distinct_variables: false,
+            exact_component: false,
+            alternatives: vec![0],
+        };
+        let take = Transition::Consume(BLUE, ColorPredicate::Exact(BLUE));
+
+        let good = fsm(vec![vec![(1, take.clone())], vec![(2, Transition::Capture(0))], vec![(3, Transition::Round(0))], vec![(4, Transition::EndCapture(0))], vec![]]);
+        assert!(good.validate().is_ok());
+
+        let unopened = fsm(vec![vec![(1, take.clone())], vec![(2, Transition::EndCapture(3))], vec![]]);
+        assert_eq!(unopened.problems(), vec![Problem::EndsUnopened{state: 1, group: 3}]);
+        // Matching it anyway doesn't fall over
+        assert!(unopened.identify(&Picture{pixels: vec![BLUE], width: 1, height: 1, path: None}).is_none());
+        let mut headless = good.clone();
+        headless.colors.clear();
+        headless.states[0].t.clear();
+        assert_eq!(headless.problems(), vec![Problem::NoFunctionColor]);
+        let blue = Picture{pixels: vec![BLUE], width: 1, height: 1, path: None};
+        assert!(headless.identify(&blue).is_none());
+        assert!(headless.identify_at(&blue, Point::zero()).is_none());
+        assert!(headless.find_all(&blue).is_empty());
+
+        let crossed = fsm(vec![vec![(1, Transition::Capture(0))], vec![(2, Transition::Capture(1))], vec![(3, Transition::EndCapture(0))], vec![(4, Transition::EndCapture(1))], vec![]]);
+        assert_eq!(crossed.problems(), vec![Problem::EndsOutOfOrder{state: 2, group: 0}]);
+
+        let left_open = fsm(vec![vec![(1, Transition::Capture(2))], vec![(2, Transition::Round(5))], vec![]]);
+        assert_eq!(left_open.problems(), vec![Problem::RoundUnopened{state: 1, group: 5}]);
+        let left_open = fsm(vec![vec![(1, Transition::Capture(2))], vec![(2, Transition::Round(2))], vec![]]);
+        assert_eq!(left_open.problems(), vec![Problem::LeftOpen{state: 2, group: 2}]);
+
+        let forever = fsm(vec![vec![(0, Transition::Capture(1)), (1, Transition::Epsilon)], vec![]]);
+        assert!(forever.problems().contains(&Problem::OpensForever{state: 0}));
+
+        // Counting rounds without taking anything never stops, bouncing straight back along an epsilon doesn't happen
+        let spinning = fsm(vec![
+            vec![(1, take.clone())],
+            vec![(2, Transition::Capture(0))],
+            vec![(3, Transition::Round(0))],
+            vec![(4, Transition::Epsilon)],
+            vec![(2, Transition::Epsilon), (5, Transition::EndCapture(0))],
+            vec![],
+        ]);
+        assert_eq!(spinning.problems(), vec![Problem::FreeLoop{state: 2}]);
+        let bouncing = fsm(vec![vec![(1, take.clone())], vec![(2, Transition::Epsilon)], vec![(1, Transition::Epsilon), (3, Transition::Epsilon)], vec![]]);
+        assert!(bouncing.validate().is_ok());
+        let text = "fsm 1\ncolor #0000ff function\nstate 0\n    -> 1 consume #0000ff\nstate 1\n    -> 2 epsilon\nstate 2\n    -> 3 epsilon\nstate 3\n    -> 1 epsilon\n    -> 4 epsilon\nstate 4\n";
+        assert!(matches!(Fsm::from_text(text), Err(Error::Parse{..})));
+
+        // Either way round the diamond skips one side, so moving relative to a side isn't safe
+        let diamond = fsm(vec![
+            vec![(1, Transition::Epsilon), (2, Transition::Epsilon)],
+            vec![(3, Transition::Epsilon)],
+            vec![(3, Transition::Epsilon)],
+            vec![(4, Transition::MoveRelative(1, Point::zero())), (4, Transition::MoveRelative(0, Point::zero()))],
+            vec![],
+        ]);
+        assert_eq!(diamond.problems(), vec![Problem::RelativeNotBefore{state: 3, relative: 1}]);
+
+        let stuck = fsm(vec![vec![(1, take.clone()), (2, Transition::Epsilon)], vec![(1, Transition::Epsilon)], vec![]]);
+        assert_eq!(stuck.problems(), vec![Problem::DeadEnd{state: 1}]);
+
+        let missing = fsm(vec![vec![(1, take.clone()), (7, Transition::MoveRelative(9, Point::zero()))], vec![]]);
+        assert_eq!(missing.problems(), vec![Problem::MissingState{state: 0, destination: 7}, Problem::MissingRelative{state: 0, relative: 9}]);
+        let err = missing.validate().unwrap_err();
+        assert!(matches!(&err, Error::Invalid{problems} if problems.len() == 2));
+        assert!(err.to_string().contains("state 0 goes to state 7 which doesn't exist"));
+
+        let mut unknown = good.clone();
+        unknown.states[0].t[0].1 = Transition::Consume(RED, ColorPredicate::Exact(RED));
+        unknown.colors.clear();
+        assert_eq!(unknown.problems(), vec![Problem::NoFunctionColor, Problem::UnknownColor{state: 0, color: RED}]);
+
+        // Loading checks the same things
+        let text = "fsm 1\ncolor #0000ff function\nstate 0\n    -> 1 end 3\nstate 1\n";
+        assert!(matches!(Fsm::from_text(text), Err(Error::Parse{..})));
+    }
+}
@@ -0,0 +1,623 @@
+#![allow(dead_code)]
+use crate::tokenizer::point::*;
+use crate::tokenizer::picture::*;
+use crate::tokenizer::fsm::{Fsm, Transition, ColorType};
+use crate::tokenizer::matched::{Match, Capture, Limit, Limits};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::time::Instant;
+
+// Pike VM style simulation of an fsm
+// Instead of backtracking, every live path (thread) is kept in a list in the order the backtracker
+// would have tried them. All the threads take their free transitions, then consume one pixel each
+// together, so a generation is one consumed pixel. Threads that end up in exactly the same
+// situation in a generation are merged, the first one wins since it would have been tried first.
+// Captures that already closed don't change where a thread can go, so they don't keep threads apart.
+// A match cuts off every thread after it but the ones before it keep going, and if one of those
+// matches later it wins, so the result is the same as the first match of the backtracker.
+
+const ROOT: u32 = 0;
+
+/// One spot in a PixelSet trie
+#[derive(Debug, Clone)]
+enum Slot {
+    Empty,
+    Leaf(Point),
+    Branch(Rc<[Slot; 16]>),
+}
+
+/// Set of points that is cheap to copy and add to, copies share everything they didn't change
+/// It's a hash trie, points are scrambled and 4 bits at a time pick which way to go
+#[derive(Debug, Clone)]
+struct PixelSet(Rc<[Slot; 16]>);
+
+impl PixelSet {
+    fn new() -> PixelSet {
+        PixelSet(Rc::new(std::array::from_fn(|_| Slot::Empty)))
+    }
+
+    /// Scrambles a point so nearby points spread out, no two points scramble the same
+    fn key(p: Point) -> u64 {
+        let mut k = ((p.x as u32 as u64) << 32) | p.y as u32 as u64;
+        k = (k ^ (k >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        k = (k ^ (k >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return k ^ (k >> 31);
+    }
+
+    fn contains(&self, p: Point) -> bool {
+        let k = PixelSet::key(p);
+        let mut branch = &self.0;
+        let mut shift = 0;
+        loop {
+            match &branch[((k >> shift) & 15) as usize] {
+                Slot::Empty => return false,
+                Slot::Leaf(q) => return *q == p,
+                Slot::Branch(b) => branch = b,
+            }
+            shift += 4;
+        }
+    }
+
+    /// Copy of the set with p in it
+    fn insert(&self, p: Point) -> PixelSet {
+        fn go(branch: &[Slot; 16], p: Point, k: u64, shift: u32) -> [Slot; 16] {
+            let mut new_branch = branch.clone();
+            let i = ((k >> shift) & 15) as usize;
+            new_branch[i] = match &branch[i] {
+                Slot::Empty => Slot::Leaf(p),
+                Slot::Leaf(q) if *q == p => Slot::Leaf(p),
+                // Two points want the same spot, push the old one down a level and try again
+                Slot::Leaf(q) => {
+                    let mut below: [Slot; 16] = std::array::from_fn(|_| Slot::Empty);
+                    below[((PixelSet::key(*q) >> (shift + 4)) & 15) as usize] = Slot::Leaf(*q);
+                    Slot::Branch(Rc::new(go(&below, p, k, shift + 4)))
+                }
+                Slot::Branch(b) => Slot::Branch(Rc::new(go(b, p, k, shift + 4))),
+            };
+            return new_branch;
+        }
+        return PixelSet(Rc::new(go(&self.0, p, PixelSet::key(p), 0)));
+    }
+}
+
+/// One pixel consumed by some thread, threads share their history through the parent links
+#[derive(Debug, Clone)]
+struct Node {
+    point: Point,
+    color: Color,
+    parent: u32,
+    // Everything consumed up to and including this pixel
+    consumed: PixelSet,
+}
+
+/// Every pixel consumed by any thread, stored as a tree
+struct Trail {
+    nodes: Vec<Node>,
+    // Same parent consuming the same pixel for the same color gets the same node
+    interned: HashMap<(u32, Point, Color), u32>,
+}
+
+impl Trail {
+    fn new() -> Trail {
+        // Root is a fake node standing for nothing consumed yet
+        let root = Node{point: Point::zero(), color: WHITE, parent: ROOT, consumed: PixelSet::new()};
+        Trail{nodes: vec![root], interned: HashMap::new()}
+    }
+
+    /// Adds a pixel to the end of a history
+    fn push(&mut self, parent: u32, point: Point, color: Color) -> u32 {
+        if let Some(n) = self.interned.get(&(parent, point, color)) {
+            return *n;
+        }
+        let consumed = self.nodes[parent as usize].consumed.insert(point);
+        let n = self.nodes.len() as u32;
+        self.nodes.push(Node{point, color, parent, consumed});
+        self.interned.insert((parent, point, color), n);
+        return n;
+    }
+
+    /// Whether the history ending at n has consumed a point
+    fn contains(&self, n: u32, point: Point) -> bool {
+        return self.nodes[n as usize].consumed.contains(point);
+    }
+
+    /// Pixels consumed after start up to and including end, start has to be an ancestor of end
+    fn between(&self, start: u32, mut end: u32) -> Vec<Point> {
+        let mut order = vec![];
+        while end != start && end != ROOT {
+            order.push(self.nodes[end as usize].point);
+            end = self.nodes[end as usize].parent;
+        }
+        order.reverse();
+        return order;
+    }
+
+    /// Turns a history back into the pixels it consumed, in consumption order
+    fn collect(&self, mut n: u32) -> Vec<(Color, Point)> {
+        let mut order = vec![];
+        while n != ROOT {
+            let node = &self.nodes[n as usize];
+            order.push((node.color, node.point));
+            n = node.parent;
+        }
+        order.reverse();
+        return order;
+    }
+}
+
+/// Everything a path through the fsm needs to know to keep going
+#[derive(Debug, Clone)]
+struct Thread {
+    state: usize,
+    head: Point,
+    // State we came from if we got here for free, so we don't bounce straight back
+    epsilon: Option<usize>,
+    // Head position last time each state that gets moved relative to was entered
+    points: Rc<Vec<Point>>,
+    // Pixels consumed so far
+    trail: u32,
+    // Open capture groups, how many times they went round, and the trail when they were opened
+    capture_groups: Vec<(u8, i32, u32)>,
+    // Lengths of capture groups that have been closed, sorted by group
+    ended_groups: Vec<(u8, i32)>,
+    // Every capture that has been closed in the order they closed, with where they started and stopped on the trail
+    // and how many groups were still open around it
+    spans: Vec<(u8, i32, u32, u32, usize)>,
+    // Picture color each variable has bound to, sorted by variable
+    bindings: Vec<(Color, Color)>,
+}
+
+// Threads that only differ in their spans are the same thread, spans only end up in the match so they
+// can't change where a thread goes, and the first one is kept since it would have matched first anyway
+impl PartialEq for Thread {
+    fn eq(&self, other: &Thread) -> bool {
+        return self.state == other.state && self.head == other.head && self.epsilon == other.epsilon && self.points == other.points
+            && self.trail == other.trail && self.capture_groups == other.capture_groups && self.ended_groups == other.ended_groups
+            && self.bindings == other.bindings;
+    }
+}
+
+impl Eq for Thread {}
+
+impl Hash for Thread {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.state.hash(state);
+        self.head.hash(state);
+        self.epsilon.hash(state);
+        self.points.hash(state);
+        self.trail.hash(state);
+        self.capture_groups.hash(state);
+        self.ended_groups.hash(state);
+        self.bindings.hash(state);
+    }
+}
+
+/// Limits on matching and how much of them has been used, shared by every run in one match attempt
+pub(crate) struct Budget {
+    limits: Limits,
+    steps: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Limits) -> Budget {
+        Budget{limits, steps: 0}
+    }
+
+    fn past_deadline(&self) -> bool {
+        return self.limits.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    }
+
+    /// Counts one more step depth free transitions in, gives back the limit it goes over if it does
+    fn spend(&mut self, depth: usize) -> Option<Limit> {
+        self.steps += 1;
+        if self.limits.steps.is_some_and(|max| self.steps > max) {return Some(Limit::Steps)}
+        if self.limits.depth.is_some_and(|max| depth > max) {return Some(Limit::Depth)}
+        // Looking at the clock every step would slow everything down
+        if self.steps.is_multiple_of(1024) && self.past_deadline() {return Some(Limit::Deadline)}
+        return None;
+    }
+}
+
+/// What's left to do in a generation
+enum Work {
+    Follow(Thread, usize), // Free transitions out of the thread still have to be followed, and how many free transitions deep it is
+    Consumed(Thread), // Thread consumed a pixel and waits for the next generation
+}
+
+/// Sort key for colors, they don't have an order of their own
+fn rgb(c: Color) -> (u8, u8, u8) {
+    return (c.r, c.g, c.b);
+}
+
+struct Vm<'a> {
+    f: &'a Fsm,
+    p: &'a Picture,
+    o: Orientation,
+    claimed: &'a HashSet<Point>,
+    func_color: Color,
+    // Which slot in Thread::points a state writes to, if anything ever moves relative to it
+    slots: Vec<Option<usize>>,
+    trail: Trail,
+    // Threads already seen this generation
+    seen: HashSet<Thread>,
+    // Connected pixels around the anchor a match has to consume exactly, if the fsm asks for that
+    component: Option<HashSet<Point>>,
+    budget: &'a mut Budget,
+    // Limit that was hit, the run stops as soon as there is one
+    gave_up: Option<Limit>,
+}
+
+impl Vm<'_> {
+    /// Follows every free transition out of a thread in priority order, unless that goes over the budget
+    /// Consumes go into next for the next generation, returns the thread if it finished
+    /// It's a depth first search, kept on a stack of its own so long runs of free transitions can't run out of call stack
+    fn step(&mut self, t: Thread, next: &mut Vec<Thread>) -> Option<Thread> {
+        let mut work = vec![Work::Follow(t, 0)];
+        let mut found = vec![];
+        while let Some(item) = work.pop() {
+            match item {
+                Work::Consumed(t) => next.push(t),
+                Work::Follow(t, depth) => {
+                    if let Some(limit) = self.budget.spend(depth) {
+                        self.gave_up = Some(limit);
+                        return None;
+                    }
+                    if let Some(finished) = self.follow(t, depth, &mut found) {
+                        return Some(finished);
+                    }
+                    // Backwards so the first thing found comes off the stack first
+                    work.extend(found.drain(..).rev());
+                }
+            }
+        }
+        return None;
+    }
+
+    /// Everything one thread leads to, in priority order, returns the thread if it finished
+    fn follow(&mut self, mut t: Thread, depth: usize, found: &mut Vec<Work>) -> Option<Thread> {
+        if !self.seen.insert(t.clone()) {return None}
+
+        if let Some(slot) = self.slots[t.state] {
+            Rc::make_mut(&mut t.points)[slot] = t.head;
+        }
+        let cur_state = &self.f.states[t.state];
+        // Finish state has no transitions out
+        if cur_state.t.is_empty() {
+            if let Some(component) = &self.component {
+                let consumed = self.trail.collect(t.trail);
+                if consumed.len() != component.len() || !consumed.iter().all(|(_, p)| component.contains(p)) {
+                    return None;
+                }
+            }
+            return Some(t);
+        }
+
+        for (destination, transition) in cur_state.t.iter() {
+            let destination = *destination;
+            if destination >= self.f.states.len() {continue}
+            match transition {
+                Transition::MoveRelative(rel_state, direction) => {
+                    let rel = self.slots.get(*rel_state).copied().flatten().map_or(Point::zero(), |slot| t.points[slot]);
+                    let new_head = rel + self.o.apply(*direction);
+                    if !self.p.in_bounds(new_head) {continue}
+
+                    let nt = Thread{state: destination, head: new_head, epsilon: None, ..t.clone()};
+                    found.push(Work::Follow(nt, depth + 1));
+                }
+                Transition::Consume(color, predicate) => {
+                    let head_color = self.p.get_point(t.head);
+                    if head_color == WHITE || head_color == GREEN {continue}
+                    if !predicate.matches(head_color) {continue}
+                    if self.claimed.contains(&t.head) || self.trail.contains(t.trail, t.head) {continue}
+
+                    let mut bindings = t.bindings.clone();
+                    if self.f.colors.get(color) == Some(&ColorType::Variable) {
+                        match t.bindings.binary_search_by_key(&rgb(*color), |(v, _)| rgb(*v)) {
+                            // Already bound, has to stay the same color
+                            Ok(i) => if t.bindings[i].1 != head_color {continue},
+                            Err(i) => {
+                                if head_color == self.func_color {continue}
+                                if self.f.distinct_variables && t.bindings.iter().any(|(_, bound)| *bound == head_color) {continue}
+                                bindings.insert(i, (*color, head_color));
+                            }
+                        }
+                    }
+
+                    let trail = self.trail.push(t.trail, t.head, *color);
+                    found.push(Work::Consumed(Thread{state: destination, epsilon: None, trail, bindings, ..t.clone()}));
+                }
+                Transition::Epsilon => {
+                    // Avoids infinite loop
+                    if t.epsilon == Some(destination) {continue}
+                    let nt = Thread{state: destination, epsilon: Some(t.state), ..t.clone()};
+                    found.push(Work::Follow(nt, depth + 1));
+                }
+                Transition::NotAt(offset, predicate) => {
+                    // Off the edge of the picture counts as not there
+                    let at = t.head + self.o.apply(*offset);
+                    if self.p.in_bounds(at) && predicate.matches(self.p.get_point(at)) {continue}
+                    let nt = Thread{state: destination, epsilon: Some(t.state), ..t.clone()};
+                    found.push(Work::Follow(nt, depth + 1));
+                }
+                Transition::Capture(g) => {
+                    let mut nt = Thread{state: destination, epsilon: Some(t.state), ..t.clone()};
+                    nt.capture_groups.push((*g, 0, t.trail));
+                    found.push(Work::Follow(nt, depth + 1));
+                }
+                Transition::Round(g) => {
+                    let mut nt = Thread{state: destination, epsilon: Some(t.state), ..t.clone()};
+                    // Innermost open group with that number
+                    if let Some(open) = nt.capture_groups.iter_mut().rev().find(|(x, _, _)| x == g) {
+                        open.1 += 1;
+                    }
+                    found.push(Work::Follow(nt, depth + 1));
+                }
+                Transition::EndCapture(g) => {
+                    let g = *g;
+                    // Innermost open group with that number, if it was never opened there's nothing to end
+                    let Some(open) = t.capture_groups.iter().rposition(|(x, _, _)| *x == g) else {continue};
+                    let (_, c, start) = t.capture_groups[open];
+
+                    let mut nt = Thread{state: destination, epsilon: Some(t.state), ..t.clone()};
+                    // Groups with the same number have to be the same length
+                    match t.ended_groups.binary_search_by_key(&g, |(x, _)| *x) {
+                        Ok(i) => if t.ended_groups[i].1 != c {continue},
+                        Err(i) => nt.ended_groups.insert(i, (g, c)),
+                    }
+                    nt.capture_groups.remove(open);
+                    nt.spans.push((g, c, start, t.trail, nt.capture_groups.len()));
+                    found.push(Work::Follow(nt, depth + 1));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Runs an fsm turned by o with its head at anchor, without consuming anything in claimed
+/// Gives back the same match the first successful path of a backtracking search would,
+/// or the limit it hit if it ran out of budget before it could tell
+pub(crate) fn run(f: &Fsm, p: &Picture, anchor: Point, o: Orientation, claimed: &HashSet<Point>, budget: &mut Budget) -> Result<Option<Match>, Limit> {
+    if budget.past_deadline() {return Err(Limit::Deadline)}
+    if f.states.is_empty() {return Ok(None)}
+    let Some(func_color) = f.function_color() else {return Ok(None)};
+
+    // Only states something moves relative to need their head position remembered
+    let mut slots = vec![None; f.states.len()];
+    slots[0] = Some(0);
+    let mut count = 1;
+    for state in f.states.iter() {
+        for (_, transition) in state.t.iter() {
+            if let Transition::MoveRelative(rel_state, _) = transition {
+                if *rel_state < slots.len() && slots[*rel_state].is_none() {
+                    slots[*rel_state] = Some(count);
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    // Pixels earlier matches took belong to them, not to this component
+    let component = if f.exact_component {Some(p.component(anchor, claimed))} else {None};
+    let mut vm = Vm{f, p, o, claimed, func_color, slots, trail: Trail::new(), seen: HashSet::new(), component, budget, gave_up: None};
+    let mut points = vec![Point::zero(); count];
+    points[0] = anchor;
+    let start = Thread{
+        state: 0,
+        head: anchor,
+        epsilon: None,
+        points: Rc::new(points),
+        trail: ROOT,
+        capture_groups: vec![],
+        ended_groups: vec![],
+        spans: vec![],
+        bindings: vec![],
+    };
+
+    let mut found = None;
+    let mut current = vec![start];
+    while !current.is_empty() {
+        vm.seen.clear();
+        let mut next = vec![];
+        for t in current {
+            // Everything after a match has lower priority than it, so drop it
+            if let Some(result) = vm.step(t, &mut next) {
+                found = Some(result);
+                break;
+            }
+            if let Some(limit) = vm.gave_up {
+                return Err(limit);
+            }
+        }
+        current = next;
+    }
+
+    return Ok(found.map(|t| {
+        // Groups that close inside another one close before it, so they're waiting one level down when it does
+        let mut levels: Vec<Vec<Capture>> = vec![vec![]];
+        for (group, count, start, end, depth) in t.spans.iter().copied() {
+            levels.resize_with(levels.len().max(depth + 2), Vec::new);
+            let children = std::mem::take(&mut levels[depth + 1]);
+            levels[depth].push(Capture{group, count: count as usize, pixels: vm.trail.between(start, end), children});
+        }
+        let captures = std::mem::take(&mut levels[0]);
+        Match::new(anchor, o, vm.trail.collect(t.trail), f.colors.clone(), captures, t.bindings.iter().copied().collect(), f.alternative_of(t.state))
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenizer::fsm::tests::{definitions, legend};
+    use crate::tokenizer::matched::Outcome;
+    use crate::tokenizer::pattern::Pattern;
+    use super::*;
+
+    #[test]
+    /// Checks that threads that only differ in captures they already closed are merged and the first one is kept
+    fn merges_closed_captures() {
+        let legend = legend();
+        // Every input is either captured or comes after an empty capture, that's 2^20 ways through
+        let input = Pattern::Seq(vec![
+            Pattern::Move(Point::from(1, 0)),
+            Pattern::Alt(vec![
+                Pattern::capture(1, Pattern::Consume(YELLOW)),
+                Pattern::Seq(vec![Pattern::capture(1, Pattern::Seq(vec![])), Pattern::Consume(YELLOW)]),
+            ]),
+        ]);
+        let mut seq = vec![Pattern::Consume(BLUE)];
+        seq.extend(std::iter::repeat_n(input, 20));
+        let fsm = Fsm::from_pattern(BLUE, Pattern::Seq(seq)).add_input(YELLOW).build().unwrap();
+        let picture = Picture::from_ascii(&format!("F{}", "i".repeat(20)), &legend).unwrap();
+
+        let Outcome::Found(found) = fsm.identify_within(&picture, Limits{steps: Some(10_000), ..Limits::default()}) else {
+            panic!("should match without going every way through");
+        };
+        let captured: Vec<Vec<Point>> = found.groups(1).map(|c| c.pixels.clone()).collect();
+        assert_eq!(captured, (1..=20).map(|x| vec![Point::from(x, 0)]).collect::<Vec<_>>());
+    }
+
+    /// One path through an fsm for the backtracking search below
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Path {
+        state: usize,
+        head: Point,
+        epsilon: Option<usize>,
+        // Head position last time each state was entered, only kept for states something moves relative to
+        points: Vec<Point>,
+        pixels: Vec<(Color, Point)>,
+        // Group, rounds and how many pixels had been consumed when it opened
+        open: Vec<(u8, usize, usize)>,
+        ended: Vec<(u8, usize)>,
+        // Group, rounds and the pixels it consumed, in the order they closed
+        closed: Vec<(u8, usize, Vec<Point>)>,
+        bindings: Vec<(Color, Color)>,
+    }
+
+    /// Plain depth first backtracking, the way matching worked before the vm, tries transitions in order
+    /// Paths already tried are skipped since they'd go the same way again
+    fn backtrack(f: &Fsm, p: &Picture, o: Orientation, component: &Option<HashSet<Point>>, seen: &mut HashSet<Path>, mut path: Path) -> Option<Path> {
+        if !seen.insert(path.clone()) {return None}
+        let relative = path.state == 0 || f.states.iter().flat_map(|state| state.t.iter()).any(|(_, t)| matches!(t, Transition::MoveRelative(r, _) if *r == path.state));
+        if relative {
+            path.points[path.state] = path.head;
+        }
+        let state = &f.states[path.state];
+        if state.t.is_empty() {
+            if let Some(component) = component {
+                if path.pixels.len() != component.len() || !path.pixels.iter().all(|(_, q)| component.contains(q)) {return None}
+            }
+            return Some(path);
+        }
+        for (destination, transition) in state.t.iter() {
+            let mut next = Path{state: *destination, epsilon: Some(path.state), ..path.clone()};
+            match transition {
+                Transition::MoveRelative(rel, direction) => {
+                    next.head = path.points[*rel] + o.apply(*direction);
+                    next.epsilon = None;
+                    if !p.in_bounds(next.head) {continue}
+                }
+                Transition::Consume(color, predicate) => {
+                    let c = p.get_point(path.head);
+                    if c == WHITE || c == GREEN || !predicate.matches(c) || path.pixels.iter().any(|(_, q)| *q == path.head) {continue}
+                    if f.colors.get(color) == Some(&ColorType::Variable) {
+                        match path.bindings.iter().find(|(v, _)| v == color) {
+                            Some((_, bound)) => if *bound != c {continue},
+                            None => {
+                                if Some(c) == f.function_color() {continue}
+                                if f.distinct_variables && path.bindings.iter().any(|(_, bound)| *bound == c) {continue}
+                                next.bindings.push((*color, c));
+                            }
+                        }
+                    }
+                    next.pixels.push((*color, path.head));
+                    next.epsilon = None;
+                }
+                Transition::Epsilon => if path.epsilon == Some(*destination) {continue},
+                Transition::NotAt(offset, predicate) => {
+                    let at = path.head + o.apply(*offset);
+                    if p.in_bounds(at) && predicate.matches(p.get_point(at)) {continue}
+                }
+                Transition::Capture(g) => next.open.push((*g, 0, path.pixels.len())),
+                Transition::Round(g) => {
+                    if let Some(open) = next.open.iter_mut().rev().find(|(x, _, _)| x == g) {
+                        open.1 += 1;
+                    }
+                }
+                Transition::EndCapture(g) => {
+                    let Some(i) = path.open.iter().rposition(|(x, _, _)| x == g) else {continue};
+                    let (_, count, start) = next.open.remove(i);
+                    match path.ended.iter().find(|(x, _)| x == g) {
+                        Some((_, c)) => if *c != count {continue},
+                        None => next.ended.push((*g, count)),
+                    }
+                    next.closed.push((*g, count, path.pixels[start..].iter().map(|(_, q)| *q).collect()));
+                }
+            }
+            if let Some(found) = backtrack(f, p, o, component, seen, next) {
+                return Some(found);
+            }
+        }
+        return None;
+    }
+
+    #[test]
+    /// Checks that the vm finds the same first match a backtracking search does, captures and all
+    fn matches_like_backtracking() {
+        let definitions = definitions();
+        let input = Color::from(0, 148, 255);
+        let output = Color::from(178, 0, 255);
+        let mut fsms = vec![];
+        for def in definitions.iter() {
+            fsms.push(Fsm::builder(def).unwrap().add_input(input).add_output(output).build().unwrap());
+            fsms.push(Fsm::builder(def).unwrap().add_input(input).add_output(output).any_orientation().exact_component().build().unwrap());
+        }
+
+        // Every definition turned around, and with one pixel at a time knocked out or swapped for another color
+        let mut pictures = vec![];
+        for def in definitions.iter() {
+            for o in Orientation::ALL {
+                pictures.push(def.orient(o));
+            }
+            for (i, c) in def.pixels.iter().enumerate() {
+                if *c == WHITE {continue}
+                for swap in [WHITE, input, output] {
+                    let mut p = def.clone();
+                    p.pixels[i] = swap;
+                    pictures.push(p);
+                }
+            }
+        }
+
+        let mut found = 0;
+        for fsm in fsms.iter() {
+            for p in pictures.iter() {
+                let mut expected = None;
+                for o in fsm.orientations.iter() {
+                    let Some(head) = fsm.head_for(p, *o) else {continue};
+                    let mut points = vec![Point::zero(); fsm.states.len()];
+                    points[0] = head;
+                    let start = Path{state: 0, head, epsilon: None, points, pixels: vec![], open: vec![], ended: vec![], closed: vec![], bindings: vec![]};
+                    let component = if fsm.exact_component {Some(p.component(head, &HashSet::new()))} else {None};
+                    if let Some(path) = backtrack(fsm, p, *o, &component, &mut HashSet::new(), start) {
+                        expected = Some((head, *o, path));
+                        break;
+                    }
+                }
+
+                let matched = fsm.identify(p);
+                assert_eq!(matched.is_some(), expected.is_some());
+                let (Some(matched), Some((head, o, path))) = (matched, expected) else {continue};
+                found += 1;
+                assert_eq!((matched.anchor, matched.orientation), (head, o));
+                assert_eq!(matched.pixels, path.pixels);
+                let captures: Vec<(u8, usize, Vec<Point>)> = matched.all_captures().into_iter().map(|c| (c.group, c.count, c.pixels.clone())).collect();
+                assert_eq!(captures, path.closed);
+                assert_eq!(matched.bindings, path.bindings.into_iter().collect());
+            }
+        }
+        // Plenty of both
+        assert!(found > 100 && found < fsms.len() * pictures.len());
+    }
+}